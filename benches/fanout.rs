@@ -0,0 +1,118 @@
+//! Ping fan-out: every player is subscribed to every other player and each of them sends one ping.
+//! "tasks" is the old model with a broadcast channel per player and a task per subscription,
+//! "outboxes" is the current one, see src/ws/broadcasts.rs.
+
+use std::sync::Arc;
+
+use bytes::Bytes;
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use tokio::{runtime::Runtime, sync::{broadcast, mpsc}};
+use uuid::Uuid;
+
+#[allow(dead_code)]
+#[path = "../src/ws/broadcasts.rs"]
+mod broadcasts;
+use broadcasts::{Broadcasts, Outbox};
+
+const PING: [u8; 64] = [1; 64];
+
+struct Tasks {
+    publishers: Vec<broadcast::Sender<Vec<u8>>>,
+    connections: Vec<mpsc::Receiver<Vec<u8>>>,
+}
+
+impl Tasks {
+    fn new(players: usize) -> Self {
+        let publishers: Vec<_> = (0..players).map(|_| broadcast::channel(64).0).collect();
+        let mut connections = Vec::new();
+        for player in 0..players {
+            let (mtx, mrx) = mpsc::channel(64);
+            for (other, tx) in publishers.iter().enumerate() {
+                if other == player {
+                    continue;
+                }
+                let (mut rx, mtx) = (tx.subscribe(), mtx.clone());
+                tokio::spawn(async move {
+                    while let Ok(msg) = rx.recv().await {
+                        if mtx.send(msg).await.is_err() {
+                            return;
+                        }
+                    }
+                });
+            }
+            connections.push(mrx);
+        }
+        Self { publishers, connections }
+    }
+
+    async fn round(&mut self) {
+        for tx in &self.publishers {
+            tx.send(PING.to_vec()).unwrap();
+        }
+        let expected = self.publishers.len() - 1;
+        for mrx in &mut self.connections {
+            for _ in 0..expected {
+                mrx.recv().await.unwrap();
+            }
+        }
+    }
+}
+
+struct Outboxes {
+    broadcasts: Broadcasts,
+    players: Vec<(Uuid, Arc<Outbox>)>,
+    dropped: u64,
+}
+
+impl Outboxes {
+    fn new(players: usize) -> Self {
+        let broadcasts = Broadcasts::default();
+        let players: Vec<_> = (0..players).map(|i| (Uuid::from_u128(i as u128), Arc::new(Outbox::default()))).collect();
+        for (uuid, _) in &players {
+            broadcasts.publish(*uuid);
+            for (other, outbox) in &players {
+                if other != uuid {
+                    broadcasts.subscribe(*uuid, outbox);
+                }
+            }
+        }
+        Self { broadcasts, players, dropped: 0 }
+    }
+
+    async fn round(&mut self) {
+        // Dropped pings never arrive, so they count towards the round too
+        let before: Vec<_> = self.players.iter().map(|(_, outbox)| outbox.take_lags().1).collect();
+        for (uuid, _) in &self.players {
+            self.broadcasts.send(*uuid, Bytes::from_static(&PING));
+        }
+        let expected = self.players.len() as u64 - 1;
+        for ((_, outbox), skipped) in self.players.iter().zip(before) {
+            let dropped = outbox.take_lags().1 - skipped;
+            for _ in dropped..expected {
+                outbox.recv().await;
+            }
+            self.dropped += dropped;
+        }
+    }
+}
+
+fn fanout(c: &mut Criterion) {
+    let rt = Runtime::new().unwrap();
+    let mut group = c.benchmark_group("fanout");
+    for players in [10, 50, 100] {
+        let mut tasks = rt.block_on(async { Tasks::new(players) });
+        group.bench_with_input(BenchmarkId::new("tasks", players), &players, |b, _| {
+            b.iter(|| rt.block_on(tasks.round()))
+        });
+        drop(tasks);
+        let mut outboxes = Outboxes::new(players);
+        group.bench_with_input(BenchmarkId::new("outboxes", players), &players, |b, _| {
+            b.iter(|| rt.block_on(outboxes.round()))
+        });
+        println!("fanout/outboxes/{players}: {} pings dropped", outboxes.dropped);
+    }
+    group.finish();
+}
+
+criterion_group!(benches, fanout);
+criterion_main!(benches);
@@ -0,0 +1,139 @@
+use axum::{extract::{FromRequestParts, Path, State}, http::{request::Parts, StatusCode}, routing::{get, post}, Json, Router};
+use chrono::Utc;
+use log::{info, warn};
+use ring::hmac;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use uuid::Uuid;
+
+use crate::{auth::{revoke_all, session::session_id, session_ttl}, ws::{connections, send_to, S2CMessage, SessionMessage}, AppState};
+
+pub fn router() -> Router<AppState> {
+    Router::new()
+        .route("/sessions/{uuid}", get(sessions).delete(revoke_sessions))
+        .route("/push", post(push))
+}
+
+/// Lists stored sessions of the user. Signed tokens aren't stored, so they aren't listed
+async fn sessions(
+    _: Admin,
+    Path(uuid): Path<Uuid>,
+    State(state): State<AppState>,
+) -> Json<Value> {
+    let online: Vec<String> = connections(&state, Some(&[uuid])).await.iter().map(|conn| session_id(&conn.token)).collect();
+    let ttl = session_ttl(&state);
+    let sessions: Vec<Value> = state.authenticated.find(uuid).await
+        .into_iter()
+        .map(|(id, userinfo)| json!({
+            "id": id.chars().take(8).collect::<String>(),
+            "username": userinfo.username,
+            "authSystem": userinfo.auth_system,
+            "created": userinfo.created,
+            "age": (Utc::now() - userinfo.created).num_seconds(),
+            "expires": userinfo.created + ttl,
+            "online": online.contains(&id),
+        }))
+        .collect();
+    Json(json!({ "uuid": uuid, "sessions": sessions }))
+}
+
+/// Revokes every token of the user and disconnects them
+async fn revoke_sessions(
+    _: Admin,
+    Path(uuid): Path<Uuid>,
+    State(state): State<AppState>,
+) -> Json<Value> {
+    let revoked = revoke_all(&state, uuid).await;
+    info!("[Admin] Revoked {revoked} session(s) of {uuid}");
+    Json(json!({ "revoked": revoked }))
+}
+
+#[derive(Deserialize)]
+struct Push {
+    target: Target,
+    message: PushMessage,
+}
+
+/// Who gets a message: `"all"`, `{"uuid": "..."}` or `{"uuids": [...]}`
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "lowercase")]
+enum Target {
+    All,
+    Uuid(Uuid),
+    Uuids(Vec<Uuid>),
+}
+
+impl Target {
+    /// UUIDs for `ws::send_to`
+    fn uuids(&self) -> Option<&[Uuid]> {
+        match self {
+            Self::All => None,
+            Self::Uuid(uuid) => Some(std::slice::from_ref(uuid)),
+            Self::Uuids(uuids) => Some(uuids),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(tag = "type", rename_all = "lowercase")]
+enum PushMessage {
+    Toast {
+        #[serde(default)]
+        kind: u8,
+        header: String,
+        description: Option<String>,
+    },
+    Chat {
+        text: String,
+    },
+    Notice {
+        kind: u8,
+    },
+}
+
+/// Sends Toast, Chat or Notice to online players
+async fn push(
+    _: Admin,
+    State(state): State<AppState>,
+    Json(push): Json<Push>,
+) -> Result<Json<Value>, (StatusCode, &'static str)> {
+    let msg = match &push.message {
+        PushMessage::Toast { header, .. } if header.contains('\0') => {
+            return Err((StatusCode::BAD_REQUEST, "header can't contain NUL"));
+        },
+        PushMessage::Toast { kind, header, description } => S2CMessage::Toast(*kind, header, description.as_deref()),
+        PushMessage::Chat { text } => S2CMessage::Chat(text),
+        PushMessage::Notice { kind } => S2CMessage::Notice(*kind),
+    };
+    let delivered = send_to(&state, push.target.uuids(), SessionMessage::Binary(msg.to_bytes())).await;
+    info!("[Admin] Pushed {:?} to {:?}, delivered to {delivered} connection(s)", push.message, push.target);
+    Ok(Json(json!({ "delivered": delivered })))
+}
+
+// Экстрактор
+/// Checks the "Authorization: Bearer <token>" header against the admin token
+pub struct Admin;
+
+impl FromRequestParts<AppState> for Admin {
+    type Rejection = StatusCode;
+
+    async fn from_request_parts(parts: &mut Parts, state: &AppState) -> Result<Self, Self::Rejection> {
+        let expected = state.config.admin.token.as_deref().ok_or(StatusCode::NOT_FOUND)?;
+        let provided = parts
+            .headers
+            .get("authorization")
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .ok_or(StatusCode::UNAUTHORIZED)?;
+        // Compares MACs of the tokens, hmac::verify takes constant time
+        let key = hmac::Key::new(hmac::HMAC_SHA256, expected.as_bytes());
+        match hmac::verify(&key, provided.as_bytes(), hmac::sign(&key, expected.as_bytes()).as_ref()) {
+            Ok(()) => Ok(Self),
+            Err(_) => {
+                warn!("[Admin] Request with wrong admin token!");
+                Err(StatusCode::UNAUTHORIZED)
+            },
+        }
+    }
+}
+// Конец экстрактора
@@ -0,0 +1,251 @@
+use chrono::{DateTime, Datelike, DurationRound, TimeDelta, Timelike, Utc};
+use log::{info, warn};
+use tokio::sync::mpsc;
+use uuid::Uuid;
+
+use crate::{config::{Announcement, Audience, Rank}, utils::format_uuid, ws::{online, send_to, S2CMessage, SessionMessage}, AppState};
+
+/// Schedule in crontab format: minute, hour, day of month, month and day of week (0 or 7 is Sunday), in UTC.
+/// Every field is `*`, a number, a range `1-5`, a step `*/15` or `0-30/10`, or a list of them `0,30`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Cron {
+    fields: [u64; 5], // Bit masks of allowed values
+    any_day: (bool, bool), // Day of month and day of week are `*`
+}
+
+const BOUNDS: [(u32, u32); 5] = [(0, 59), (0, 23), (1, 31), (1, 12), (0, 7)];
+
+impl TryFrom<String> for Cron {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        let parts: Vec<&str> = value.split_whitespace().collect();
+        if parts.len() != 5 {
+            return Err(format!("schedule \"{value}\" must have 5 fields"));
+        }
+        let mut fields = [0; 5];
+        for (i, part) in parts.iter().enumerate() {
+            fields[i] = parse_field(part, BOUNDS[i]).map_err(|e| format!("schedule \"{value}\": {e}"))?;
+        }
+        // Sunday is both 0 and 7
+        if fields[4] & 1 << 7 != 0 {
+            fields[4] |= 1;
+        }
+        Ok(Self { fields, any_day: (parts[2] == "*", parts[4] == "*") })
+    }
+}
+
+fn parse_field(field: &str, (min, max): (u32, u32)) -> Result<u64, String> {
+    let mut mask = 0;
+    for part in field.split(',') {
+        let (range, step) = match part.split_once('/') {
+            Some((range, step)) => (range, step.parse::<u32>().map_err(|_| format!("bad step in {part}"))?),
+            None => (part, 1),
+        };
+        let number = |s: &str| s.parse::<u32>().map_err(|_| format!("bad number in {part}"));
+        let (from, to) = match range.split_once('-') {
+            _ if range == "*" => (min, max),
+            Some((from, to)) => (number(from)?, number(to)?),
+            None if step != 1 => (number(range)?, max), // `5/10` is `5-max/10`
+            None => (number(range)?, number(range)?),
+        };
+        if from < min || to > max || from > to || step == 0 {
+            return Err(format!("{part} is out of {min}-{max}"));
+        }
+        for value in (from..=to).step_by(step as usize) {
+            mask |= 1 << value;
+        }
+    }
+    Ok(mask)
+}
+
+impl Cron {
+    pub fn matches(&self, time: DateTime<Utc>) -> bool {
+        let has = |field: usize, value: u32| self.fields[field] & 1 << value != 0;
+        let (dom, dow) = (has(2, time.day()), has(4, time.weekday().num_days_from_sunday()));
+        // Like crontab: if both days are restricted, either of them is enough
+        let day = match self.any_day {
+            (false, false) => dom || dow,
+            _ => dom && dow,
+        };
+        has(0, time.minute()) && has(1, time.hour()) && day && has(3, time.month())
+    }
+}
+
+/// How far back a schedule is checked, long enough for any schedule which can fire, even on February 29
+const LOOKBACK: TimeDelta = TimeDelta::days(4 * 366);
+
+fn minute_start(time: DateTime<Utc>) -> DateTime<Utc> {
+    time.duration_trunc(TimeDelta::minutes(1)).unwrap_or(time)
+}
+
+/// Announcements with the last time each of them was delivered, kept up to date by `run`
+pub type Delivered = Vec<(Announcement, Option<DateTime<Utc>>)>;
+
+impl Announcement {
+    /// The last moment in (after, until] it has to be delivered at. Goes through the schedule minute by minute
+    fn last_fire(&self, after: DateTime<Utc>, until: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        let at = self.at.filter(|at| after < *at && *at <= until);
+        let scheduled = self.schedule.as_ref().and_then(|cron| {
+            let after = after.max(until - LOOKBACK);
+            let mut minute = minute_start(until);
+            while minute > after {
+                if cron.matches(minute) {
+                    return Some(minute);
+                }
+                minute -= TimeDelta::minutes(1);
+            }
+            None
+        });
+        at.max(scheduled)
+    }
+
+    /// Start of the `duration` seconds before `now`
+    fn window_start(&self, now: DateTime<Utc>) -> DateTime<Utc> {
+        let window = i64::try_from(self.duration).ok().and_then(TimeDelta::try_seconds).unwrap_or(TimeDelta::max_value());
+        now.checked_sub_signed(window).unwrap_or(DateTime::<Utc>::MIN_UTC)
+    }
+
+    /// Whether it was delivered at `fired` less than `duration` seconds ago
+    fn is_active(&self, fired: Option<DateTime<Utc>>, now: DateTime<Utc>) -> bool {
+        fired.is_some_and(|fired| self.window_start(now) < fired && fired <= now)
+    }
+
+    fn message(&self) -> SessionMessage {
+        SessionMessage::Binary(S2CMessage::Toast(self.kind, &self.header, self.description.as_deref()).to_bytes())
+    }
+}
+
+impl Rank {
+    /// Index in the special badges, see note.txt
+    fn badge(self) -> usize {
+        self as usize
+    }
+}
+
+async fn has_rank(state: &AppState, uuid: Uuid, rank: Rank) -> bool {
+    state.advanced_users.lock().await
+        .get(&format_uuid(uuid))
+        .and_then(|settings| settings.get("special"))
+        .and_then(|special| special.as_array())
+        .and_then(|special| special.get(rank.badge()))
+        .and_then(|badge| badge.as_integer())
+        .is_some_and(|badge| badge != 0)
+}
+
+async fn is_recipient(state: &AppState, audience: &Audience, uuid: Uuid) -> bool {
+    match audience {
+        Audience::All => true,
+        Audience::Rank(rank) => has_rank(state, uuid, *rank).await,
+        Audience::Uuids(uuids) => uuids.contains(&uuid),
+    }
+}
+
+/// Online users the announcement is for, None for everyone
+async fn recipients(state: &AppState, audience: &Audience) -> Option<Vec<Uuid>> {
+    let online: Vec<Uuid> = match audience {
+        Audience::All => return None,
+        Audience::Uuids(uuids) => return Some(uuids.clone()),
+        Audience::Rank(_) => online(state).await,
+    };
+    let mut recipients = Vec::new();
+    for uuid in online {
+        if is_recipient(state, audience, uuid).await {
+            recipients.push(uuid);
+        }
+    }
+    Some(recipients)
+}
+
+/// Pairs the current announcements with the last time they were delivered before `now`.
+/// Only new and changed ones are looked up in their schedule, the rest keep the time from `delivered`
+fn refresh(delivered: &Delivered, announcements: Vec<Announcement>, now: DateTime<Utc>) -> Delivered {
+    announcements.into_iter().map(|announcement| {
+        let fired = match delivered.iter().find(|(a, _)| *a == announcement) {
+            Some((_, fired)) => *fired,
+            None => announcement.last_fire(announcement.window_start(now), now),
+        };
+        (announcement, fired)
+    }).collect()
+}
+
+/// Delivers announcements when they are due, checked at the start of every minute
+pub async fn run(state: AppState) {
+    let mut last = Utc::now();
+    let mut delivered = refresh(&Delivered::new(), state.announcements.lock().await.clone(), last);
+    *state.delivered_announcements.lock().await = delivered.clone();
+    loop {
+        let next = minute_start(Utc::now()) + TimeDelta::minutes(1);
+        tokio::time::sleep((next - Utc::now()).to_std().unwrap_or_default()).await;
+        let now = Utc::now();
+        delivered = refresh(&delivered, state.announcements.lock().await.clone(), last);
+        for (announcement, fired) in &mut delivered {
+            let Some(time) = announcement.last_fire(last, now) else {
+                continue;
+            };
+            *fired = Some(time);
+            let recipients = recipients(&state, &announcement.audience).await;
+            let count = send_to(&state, recipients.as_deref(), announcement.message()).await;
+            info!("[Announcements] \"{}\" delivered to {count} connection(s)", announcement.header);
+        }
+        *state.delivered_announcements.lock().await = delivered.clone();
+        last = now;
+    }
+}
+
+/// Sends active announcements to a user who has just authenticated
+pub async fn on_auth(state: &AppState, uuid: Uuid, tx: &mpsc::Sender<SessionMessage>) {
+    let now = Utc::now();
+    let delivered = state.delivered_announcements.lock().await.clone();
+    for (announcement, _) in delivered.iter().filter(|(a, fired)| a.is_active(*fired, now)) {
+        if is_recipient(state, &announcement.audience, uuid).await && tx.try_send(announcement.message()).is_err() {
+            warn!("[Announcements] Can't deliver \"{}\" to {uuid}", announcement.header);
+        }
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn schedules() {
+    let time = |s: &str| s.parse::<DateTime<Utc>>().unwrap();
+    let cron = |s: &str| Cron::try_from(s.to_string()).unwrap();
+
+    // 2025-01-06 is Monday
+    assert!(cron("*/15 9-17 * * 1-5").matches(time("2025-01-06T09:45:00Z")));
+    assert!(!cron("*/15 9-17 * * 1-5").matches(time("2025-01-05T09:45:00Z")));
+    assert!(cron("0 0 * * 7").matches(time("2025-01-05T00:00:00Z")));
+    assert!(cron("0 12 1 * 1").matches(time("2025-01-06T12:00:00Z"))); // Either day
+    assert!(Cron::try_from(String::from("60 * * * *")).is_err());
+    assert!(Cron::try_from(String::from("* * *")).is_err());
+
+    let announcement = Announcement {
+        header: String::from("Restart"),
+        description: None,
+        kind: 0,
+        schedule: Some(cron("30 * * * *")),
+        at: Some(time("2025-01-06T10:00:10Z")),
+        duration: 600,
+        audience: Audience::All,
+    };
+    assert_eq!(announcement.last_fire(time("2025-01-06T09:29:00Z"), time("2025-01-06T09:30:00.5Z")), Some(time("2025-01-06T09:30:00Z")));
+    assert_eq!(announcement.last_fire(time("2025-01-06T09:30:00.5Z"), time("2025-01-06T09:31:00Z")), None);
+    assert_eq!(announcement.last_fire(time("2025-01-06T10:00:00Z"), time("2025-01-06T10:01:00Z")), Some(time("2025-01-06T10:00:10Z")));
+    let fired = Some(time("2025-01-06T09:30:00Z"));
+    assert!(announcement.is_active(fired, time("2025-01-06T09:39:00Z")));
+    assert!(!announcement.is_active(fired, time("2025-01-06T09:41:00Z")));
+
+    // Only new announcements are looked up in their schedule, the others keep their time
+    let now = time("2025-01-06T09:35:00Z");
+    let delivered = refresh(&Delivered::new(), vec![announcement.clone()], now);
+    assert_eq!(delivered, vec![(announcement.clone(), fired)]);
+    let kept = Some(time("2025-01-06T09:00:00Z"));
+    assert_eq!(refresh(&vec![(announcement.clone(), kept)], vec![announcement.clone()], now), vec![(announcement.clone(), kept)]);
+
+    // Huge durations mean "always since delivered"
+    let forever = Announcement { duration: u64::MAX, ..announcement };
+    let now = time("2030-01-01T00:00:00Z");
+    assert!(forever.is_active(forever.last_fire(forever.window_start(now), now), now));
+    let once = Announcement { schedule: None, ..forever };
+    let now = time("2025-01-06T10:00:00Z");
+    assert!(!once.is_active(once.last_fire(once.window_start(now), now), now));
+}
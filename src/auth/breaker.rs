@@ -0,0 +1,117 @@
+use std::{sync::Mutex, time::{Duration, Instant}};
+
+use log::{info, warn};
+
+/// Stops querying a provider that keeps failing.
+///
+/// Closed: requests go through, failures are counted.
+/// Open: requests are rejected until the cooldown ends.
+/// Half-open: after the cooldown a single request is let through to check the provider.
+#[derive(Debug)]
+pub struct CircuitBreaker {
+    name: String,
+    threshold: u32,
+    cooldown: Duration,
+    state: Mutex<BreakerState>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum BreakerState {
+    Closed(u32), // Consecutive failures
+    Open(Instant), // Until
+    HalfOpen,
+}
+
+impl CircuitBreaker {
+    pub fn new(name: String, threshold: u32, cooldown: Duration) -> Self {
+        Self { name, threshold, cooldown, state: Mutex::new(BreakerState::Closed(0)) }
+    }
+
+    /// Returns an attempt if a request to the provider may be sent now
+    pub fn allow(&self) -> Option<Attempt<'_>> {
+        let mut state = self.state.lock().unwrap();
+        match *state {
+            BreakerState::Closed(_) => Some(Attempt { breaker: self, probe: false }),
+            BreakerState::Open(until) if Instant::now() >= until => {
+                info!("[Auth {}] Circuit breaker is half-open, checking the provider", self.name);
+                *state = BreakerState::HalfOpen;
+                Some(Attempt { breaker: self, probe: true })
+            },
+            // Someone is already checking the provider
+            BreakerState::Open(_) | BreakerState::HalfOpen => None,
+        }
+    }
+
+    fn success(&self) {
+        let mut state = self.state.lock().unwrap();
+        if *state == BreakerState::HalfOpen {
+            info!("[Auth {}] Circuit breaker is closed, the provider is back", self.name);
+        }
+        *state = BreakerState::Closed(0);
+    }
+
+    fn failure(&self) {
+        let mut state = self.state.lock().unwrap();
+        let failures = match *state {
+            BreakerState::Closed(failures) => failures + 1,
+            BreakerState::HalfOpen => self.threshold,
+            BreakerState::Open(_) => return,
+        };
+        if failures >= self.threshold {
+            warn!("[Auth {}] Circuit breaker is open for {:?} after {failures} failures", self.name, self.cooldown);
+            *state = BreakerState::Open(Instant::now() + self.cooldown);
+        } else {
+            *state = BreakerState::Closed(failures);
+        }
+    }
+}
+
+/// A request let through by the breaker, report its result with `success` or `failure`.
+/// If the request is cancelled before that, a half-open check counts as failed,
+/// otherwise the breaker would stay half-open and reject everything.
+#[derive(Debug)]
+#[must_use]
+pub struct Attempt<'a> {
+    breaker: &'a CircuitBreaker,
+    probe: bool, // Not reported half-open check
+}
+
+impl Attempt<'_> {
+    pub fn success(mut self) {
+        self.probe = false;
+        self.breaker.success();
+    }
+
+    pub fn failure(mut self) {
+        self.probe = false;
+        self.breaker.failure();
+    }
+}
+
+impl Drop for Attempt<'_> {
+    fn drop(&mut self) {
+        if self.probe {
+            warn!("[Auth {}] Check of the provider was cancelled", self.breaker.name);
+            self.breaker.failure();
+        }
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn circuit_breaker_cycle() {
+    let breaker = CircuitBreaker::new(String::from("test"), 2, Duration::ZERO);
+    breaker.allow().unwrap().failure();
+    breaker.allow().unwrap().failure();
+    assert!(matches!(*breaker.state.lock().unwrap(), BreakerState::Open(_)));
+    // Cooldown is zero, so the next request checks the provider
+    let probe = breaker.allow().unwrap();
+    assert!(breaker.allow().is_none());
+    // Cancelled check opens the breaker again
+    drop(probe);
+    assert!(matches!(*breaker.state.lock().unwrap(), BreakerState::Open(_)));
+    let probe = breaker.allow().unwrap();
+    assert!(breaker.allow().is_none());
+    probe.success();
+    assert_eq!(*breaker.state.lock().unwrap(), BreakerState::Closed(0));
+}
@@ -0,0 +1,100 @@
+use std::{collections::HashMap, path::PathBuf};
+
+use log::{error, info, warn};
+use ring::digest::{self, digest};
+use tokio::sync::Mutex;
+use uuid::{Builder, Uuid};
+
+use crate::{config::CollisionPolicy, AppState, Userinfo};
+
+use super::AuthError;
+
+/// Remembers which provider a UUID belongs to, so two different people with the same UUID
+/// on different providers don't share avatars and badges. Used by "reject" and "prefer" policies.
+#[derive(Debug)]
+pub struct UuidOwners {
+    path: PathBuf,
+    owners: Mutex<HashMap<Uuid, String>>, // <UUID, provider>
+}
+
+impl UuidOwners {
+    pub fn open(path: PathBuf) -> Self {
+        let owners = match std::fs::read_to_string(&path) {
+            Ok(data) => serde_json::from_str(&data).unwrap_or_else(|e| {
+                warn!("[Collision] Can't parse {}, starting from scratch: {e}", path.display());
+                HashMap::new()
+            }),
+            Err(_) => HashMap::new(),
+        };
+        Self { path, owners: Mutex::new(owners) }
+    }
+
+    /// Checks if the user may use the UUID with this provider and remembers the owner.
+    /// Returns the previous owner if the preferred provider took the UUID over
+    pub async fn claim(&self, policy: &CollisionPolicy, uuid: Uuid, provider: &str) -> Result<Option<String>, AuthError> {
+        let preferred = match policy {
+            CollisionPolicy::Shared | CollisionPolicy::Namespace(_) => return Ok(None),
+            CollisionPolicy::Reject => None,
+            CollisionPolicy::Prefer(preferred) => Some(preferred.as_str()),
+        };
+        let mut owners = self.owners.lock().await;
+        match owners.get(&uuid) {
+            Some(owner) if owner == provider => return Ok(None),
+            Some(owner) if preferred != Some(provider) => {
+                return Err(AuthError::UuidCollision(uuid, owner.clone()));
+            },
+            Some(owner) => warn!("[Collision] {uuid} is taken over by preferred {provider} from {owner}"),
+            None => info!("[Collision] {uuid} now belongs to {provider}"),
+        }
+        let previous = owners.insert(uuid, provider.to_string());
+        match serde_json::to_string(&*owners) {
+            Ok(data) => if let Err(e) = tokio::fs::write(&self.path, data).await {
+                error!("[Collision] Can't write {}: {e}", self.path.display());
+            },
+            Err(e) => error!("[Collision] Can't serialize owners: {e}"),
+        }
+        Ok(previous)
+    }
+}
+
+/// UUID under which the user's avatar and ping broadcast are stored.
+/// It's the real UUID, except for the "namespace" policy where users of every provider
+/// but the configured one get a UUID derived from the provider name.
+pub fn storage_uuid(state: &AppState, uuid: Uuid, provider: Option<&str>) -> Uuid {
+    match (&state.config.auth.collision_policy, provider) {
+        (CollisionPolicy::Namespace(own), Some(provider)) if provider != own => {
+            let data = [provider.as_bytes(), uuid.as_bytes()].concat();
+            let hash = digest(&digest::SHA1_FOR_LEGACY_USE_ONLY, &data);
+            Builder::from_sha1_bytes(hash.as_ref()[..16].try_into().unwrap()).into_uuid()
+        },
+        _ => uuid,
+    }
+}
+
+impl Userinfo {
+    pub fn storage_uuid(&self, state: &AppState) -> Uuid {
+        storage_uuid(state, self.uuid, Some(&self.auth_system))
+    }
+}
+
+#[cfg(test)]
+#[tokio::test]
+async fn claim_policies() {
+    let file = super::tests::TempFile::new("owners");
+    let owners = UuidOwners::open(file.0.clone());
+    let uuid = Uuid::from_u128(1);
+
+    let reject = CollisionPolicy::Reject;
+    assert_eq!(owners.claim(&reject, uuid, "Ely.by").await, Ok(None));
+    assert_eq!(owners.claim(&reject, uuid, "Ely.by").await, Ok(None));
+    assert_eq!(owners.claim(&reject, uuid, "Mojang").await, Err(AuthError::UuidCollision(uuid, String::from("Ely.by"))));
+
+    let prefer = CollisionPolicy::Prefer(String::from("Mojang"));
+    assert_eq!(owners.claim(&prefer, uuid, "Mojang").await, Ok(Some(String::from("Ely.by"))));
+    assert_eq!(owners.claim(&prefer, uuid, "Mojang").await, Ok(None));
+    assert_eq!(owners.claim(&prefer, uuid, "Ely.by").await, Err(AuthError::UuidCollision(uuid, String::from("Mojang"))));
+    assert_eq!(owners.claim(&CollisionPolicy::Shared, uuid, "Ely.by").await, Ok(None));
+
+    // Owners survive restart
+    assert!(UuidOwners::open(file.0.clone()).claim(&reject, uuid, "Ely.by").await.is_err());
+}
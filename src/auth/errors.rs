@@ -0,0 +1,79 @@
+use std::fmt::*;
+
+use std::time::Duration;
+
+use axum::{http::{header, StatusCode}, response::{IntoResponse, Response}};
+use uuid::Uuid;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AuthError {
+    UnknownServerId,
+    ProviderUnavailable(String, String), // (provider, reason)
+    NotJoined,
+    MalformedProviderResponse(String, String), // (provider, reason)
+    Banned(String), // reason
+    NotWhitelisted,
+    UuidCollision(Uuid, String), // (uuid, owner provider)
+    InvalidUsername,
+    RateLimited(Duration), // Retry after
+    TooManyPending,
+}
+impl Display for AuthError {
+    fn fmt(&self, fmt: &mut Formatter) -> Result {
+        match self {
+            Self::UnknownServerId => write!(fmt, "unknown or expired server id"),
+            Self::ProviderUnavailable(p, r) => write!(fmt, "provider {p} is unavailable: {r}"),
+            Self::NotJoined => write!(fmt, "player hasn't joined the server"),
+            Self::MalformedProviderResponse(p, r) => write!(fmt, "provider {p} sent malformed response: {r}"),
+            Self::Banned(r) => write!(fmt, "banned: {r}"),
+            Self::NotWhitelisted => write!(fmt, "not whitelisted"),
+            Self::UuidCollision(u, p) => write!(fmt, "{u} already belongs to a player from {p}"),
+            Self::InvalidUsername => write!(fmt, "invalid username"),
+            Self::RateLimited(d) => write!(fmt, "too many requests, retry after {}s", d.as_secs() + 1),
+            Self::TooManyPending => write!(fmt, "too many pending logins"),
+        }
+    }
+}
+impl std::error::Error for AuthError {}
+impl AuthError {
+    pub fn status_code(&self) -> StatusCode {
+        match self {
+            Self::UnknownServerId => StatusCode::BAD_REQUEST,
+            Self::ProviderUnavailable(..) => StatusCode::SERVICE_UNAVAILABLE,
+            Self::NotJoined => StatusCode::UNAUTHORIZED,
+            Self::MalformedProviderResponse(..) => StatusCode::BAD_GATEWAY,
+            Self::Banned(_) | Self::NotWhitelisted => StatusCode::FORBIDDEN,
+            Self::UuidCollision(..) => StatusCode::CONFLICT,
+            Self::InvalidUsername => StatusCode::BAD_REQUEST,
+            Self::RateLimited(_) | Self::TooManyPending => StatusCode::TOO_MANY_REQUESTS,
+        }
+    }
+}
+impl IntoResponse for AuthError {
+    fn into_response(self) -> Response {
+        match self {
+            Self::RateLimited(d) => (self.status_code(), [(header::RETRY_AFTER, (d.as_secs() + 1).to_string())], self.to_string()).into_response(),
+            _ => (self.status_code(), self.to_string()).into_response(),
+        }
+    }
+}
+
+/// Why a token isn't accepted
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SessionError {
+    Unknown, // Never issued, forged or removed
+    Expired,
+    Revoked,
+    WrongIp, // Used from another IP than it was issued to, see verifyIp
+}
+impl Display for SessionError {
+    fn fmt(&self, fmt: &mut Formatter) -> Result {
+        match self {
+            Self::Unknown => write!(fmt, "unknown token"),
+            Self::Expired => write!(fmt, "token has expired"),
+            Self::Revoked => write!(fmt, "token was revoked"),
+            Self::WrongIp => write!(fmt, "token was issued to another IP"),
+        }
+    }
+}
+impl std::error::Error for SessionError {}
@@ -0,0 +1,431 @@
+use std::{net::{IpAddr, SocketAddr}, time::{Duration, Instant}};
+
+use axum::{debug_handler, extract::{ConnectInfo, FromRequestParts, Query, State}, http::{request::Parts, HeaderMap, StatusCode}, response::{IntoResponse, Response}, routing::get, Json, Router};
+use chrono::Utc;
+use log::{debug, info, trace, warn};
+use serde::Deserialize;
+use serde_json::json;
+use ring::digest::{self, digest};
+use uuid::Uuid;
+use crate::utils::*;
+
+mod breaker;
+mod collision;
+pub use collision::{storage_uuid, UuidOwners};
+mod errors;
+pub use errors::{AuthError, SessionError};
+mod provider;
+pub use provider::AuthProviders;
+#[cfg(feature = "dev-auth")]
+mod offline;
+pub mod session;
+pub use session::SessionStore;
+mod token;
+pub use token::TokenSigner;
+mod revocation;
+pub use revocation::Revocations;
+
+use crate::{config::Ban, ws::{close_connection, CloseCode, SessionMessage}, AppState, Userinfo};
+
+pub fn router() -> Router<AppState> {
+    Router::new()
+        .route("/id", get(id))
+        .route("/verify", get(verify))
+}
+
+
+// Веб функции
+#[derive(Deserialize)]
+struct Id {username: String}
+
+#[debug_handler]
+async fn id( // First stage of authentication
+    Query(query): Query<Id>,
+    ClientIp(ip): ClientIp,
+    State(state): State<AppState>,
+) -> Result<String, AuthError> {
+    if let Err(retry_after) = state.id_limiter.check(ip) {
+        debug!("[Authorization] {ip} is requesting IDs too often");
+        return Err(AuthError::RateLimited(retry_after));
+    }
+    if !is_valid_username(&query.username) {
+        return Err(AuthError::InvalidUsername);
+    }
+    let server_id = bytes_into_string(&digest(&digest::SHA1_FOR_LEGACY_USE_ONLY, &rand()).as_ref()[0 .. 20]);
+    let pending_ttl = Duration::from_secs(state.config.auth.pending_ttl);
+    let pending = state.pending.lock().await;
+    // Expired IDs are swept only from time to time, so don't count them
+    let (total, of_user) = pending.iter()
+        .filter(|entry| entry.1.elapsed() < pending_ttl)
+        .fold((0, 0), |(total, of_user), entry| (total + 1, of_user + (entry.0 == query.username) as usize));
+    if total >= state.config.auth.max_pending || of_user >= state.config.auth.max_pending_per_username {
+        warn!("[Authorization] Too many pending IDs ({total} in total, {of_user} for {})", query.username);
+        return Err(AuthError::TooManyPending);
+    }
+    pending.insert(server_id.clone(), (query.username, Instant::now()));
+    Ok(server_id)
+}
+
+/// Up to 16 characters, no control ones. What else is allowed is up to the provider,
+/// e.g. Mojang has only letters, digits and underscores while other servers allow more
+fn is_valid_username(username: &str) -> bool {
+    (1..=16).contains(&username.chars().count()) && !username.chars().any(char::is_control)
+}
+
+#[derive(Deserialize)]
+struct Verify {id: String}
+
+#[debug_handler]
+async fn verify( // Second stage of authentication
+    Query(query): Query<Verify>,
+    ClientIp(ip): ClientIp,
+    State(state): State<AppState>,
+) -> Result<String, AuthError> {
+    if let Err(retry_after) = state.verify_limiter.check(ip) {
+        debug!("[Authorization] {ip} is verifying too often");
+        return Err(AuthError::RateLimited(retry_after));
+    }
+    let server_id = query.id.clone();
+    let pending_ttl = Duration::from_secs(state.config.auth.pending_ttl);
+    let username = match state.pending.lock().await.remove(&server_id) {
+        Some((_, (username, created))) if created.elapsed() < pending_ttl => username,
+        _ => {
+            warn!("[Authorization] Failed to verify {server_id}: {}", AuthError::UnknownServerId);
+            return Err(AuthError::UnknownServerId)
+        },
+    };
+    // With verifyIp the session server checks the IP and the token is bound to it
+    let ip = state.config.auth.verify_ip.then_some(ip);
+    match state.auth_providers.has_joined(&state.http_client, &server_id, &username, ip).await {
+        Ok((uuid, auth_system)) => {
+            if let Some(ban) = banned(&state, storage_uuid(&state, uuid, Some(&auth_system))).await {
+                warn!("[Authorization] {username} is banned: {}", ban.reason);
+                return Err(AuthError::Banned(ban.reason));
+            }
+            if !state.whitelist.lock().await.allows(uuid, &username, &auth_system) {
+                warn!("[Authorization] {username} ({uuid}, {auth_system}) is not whitelisted");
+                return Err(AuthError::NotWhitelisted);
+            }
+            match state.uuid_owners.claim(&state.config.auth.collision_policy, uuid, &auth_system).await {
+                // Everyone logged in with this UUID so far came from the previous owner
+                Ok(Some(previous)) => {
+                    let revoked = revoke_all(&state, uuid).await;
+                    info!("[Authorization] Revoked {revoked} sessions of {uuid} from {previous}");
+                },
+                Ok(None) => (),
+                Err(e) => {
+                    warn!("[Authorization] {username} can't log in using {auth_system}: {e}");
+                    return Err(e);
+                },
+            }
+            info!("[Authorization] {username} logged in using {auth_system}");
+            let userinfo = Userinfo { username, uuid, auth_system, created: Utc::now(), ip };
+            if let Some(signer) = &state.token_signer {
+                // Stateless token, any instance with the same secret accepts it
+                return Ok(signer.sign(&userinfo, userinfo.created + session_ttl(&state)));
+            }
+            // let link = state.authenticated_link.lock().await; // // Реализация поиска пользователя в HashMap по UUID
+            state.authenticated.insert(server_id.clone(), userinfo).await;
+            // link.insert(uuid, crate::AuthenticatedLink(server_id.clone())); // Реализация поиска пользователя в HashMap по UUID
+            Ok(server_id)
+        },
+        Err(e) => {
+            warn!("[Authorization] {username} failed to log in: {e}");
+            Err(e)
+        },
+    }
+}
+
+pub async fn whoami(
+    Token(token): Token,
+    ClientIp(ip): ClientIp,
+    State(state): State<AppState>,
+) -> Response {
+    match requester(&state, token, ip).await {
+        Some(userinfo) => Json(json!({
+            "username": userinfo.username,
+            "uuid": userinfo.uuid,
+            "authSystem": userinfo.auth_system,
+            "created": userinfo.created,
+            "age": (Utc::now() - userinfo.created).num_seconds(),
+            "expires": userinfo.created + session_ttl(&state),
+        })).into_response(),
+        None => (StatusCode::UNAUTHORIZED, "unauthorized").into_response(),
+    }
+}
+
+pub async fn logout(
+    Token(token): Token,
+    ClientIp(ip): ClientIp,
+    State(state): State<AppState>,
+) -> Response {
+    let token = match token {
+        Some(token) => token,
+        None => return (StatusCode::BAD_REQUEST, "bad request").into_response(),
+    };
+    if authenticate(&state, &token, ip).await.is_none() {
+        return (StatusCode::UNAUTHORIZED, "unauthorized").into_response();
+    }
+    if let Some(userinfo) = revoke(&state, &token).await {
+        info!("[Authorization] {} logged out", userinfo.username);
+    }
+    (StatusCode::OK, "ok").into_response()
+}
+
+pub async fn status(
+    Token(token): Token,
+    ClientIp(ip): ClientIp,
+    State(state): State<AppState>,
+) -> Response {
+    match token {
+        Some(token) => {
+            if authenticate(&state, &token, ip).await.is_some() {
+                // format!("ok") // 200
+                (StatusCode::OK, "ok").into_response()
+            } else {
+                // format!("unauthorized") // 401
+                (StatusCode::UNAUTHORIZED, "unauthorized").into_response()
+            }
+        },
+        None => {
+            // format!("bad request") // 400
+            (StatusCode::BAD_REQUEST, "bad request").into_response()
+        },
+    }
+}
+// Конец веб функций
+
+/// Returns the user behind the token if it exists, hasn't expired yet and is used from the IP it was issued to
+pub async fn authenticate(state: &AppState, token: &str, ip: IpAddr) -> Option<Userinfo> {
+    check_session(state, token, ip).await.ok()
+}
+
+/// Same as `authenticate`, but tells why the token isn't accepted
+pub async fn check_session(state: &AppState, token: &str, ip: IpAddr) -> Result<Userinfo, SessionError> {
+    let userinfo = match &state.token_signer {
+        Some(signer) if TokenSigner::is_signed(token) => {
+            let userinfo = signer.verify(token)?;
+            if state.revocations.is_revoked(token, &userinfo) {
+                debug!("[Authorization] Token of {} is revoked", userinfo.username);
+                return Err(SessionError::Revoked);
+            }
+            userinfo
+        },
+        _ => state.authenticated.get(token).await.ok_or(SessionError::Unknown)?,
+    };
+    if is_expired(state, &userinfo) {
+        debug!("[Authorization] Token of {} has expired", userinfo.username);
+        state.authenticated.remove(token).await;
+        Err(SessionError::Expired)
+    } else if userinfo.ip.is_some_and(|bound| bound != ip) {
+        warn!("[Authorization] Token of {} is used from {ip}, but it was issued to {}", userinfo.username, userinfo.ip.unwrap());
+        Err(SessionError::WrongIp)
+    } else {
+        Ok(userinfo)
+    }
+}
+
+/// Invalidates the token and closes the WebSocket connection which uses it
+pub async fn revoke(state: &AppState, token: &str) -> Option<Userinfo> {
+    let userinfo = match &state.token_signer {
+        Some(signer) if TokenSigner::is_signed(token) => {
+            let userinfo = signer.verify(token).ok()?;
+            state.revocations.revoke_token(token, userinfo.created + session_ttl(state));
+            userinfo
+        },
+        _ => state.authenticated.remove(token).await?,
+    };
+    close_connection(state, userinfo.uuid, Some(token), SessionMessage::Close(CloseCode::ReAuth, String::from("Session revoked"))).await;
+    Some(userinfo)
+}
+
+/// Invalidates every token of the user and closes their WebSocket connection. Returns the number of revoked stored sessions
+pub async fn revoke_all(state: &AppState, uuid: Uuid) -> usize {
+    let removed = state.authenticated.retain(&|userinfo| userinfo.uuid != uuid).await;
+    state.revocations.revoke_user(uuid);
+    close_connection(state, uuid, None, SessionMessage::Close(CloseCode::ReAuth, String::from("Session revoked"))).await;
+    removed
+}
+
+/// Returns the user behind the optional token, see `authenticate`
+pub async fn requester(state: &AppState, token: Option<String>, ip: IpAddr) -> Option<Userinfo> {
+    authenticate(state, &token?, ip).await
+}
+
+/// Returns the ban of the user if it's active
+pub async fn banned(state: &AppState, uuid: Uuid) -> Option<Ban> {
+    state.banned_users.lock().await.get(&uuid).filter(|ban| ban.is_active()).cloned()
+}
+
+pub fn session_ttl(state: &AppState) -> chrono::Duration {
+    chrono::Duration::hours(state.config.auth.session_ttl as i64)
+}
+
+fn is_expired(state: &AppState, userinfo: &Userinfo) -> bool {
+    userinfo.created + session_ttl(state) < Utc::now()
+}
+
+/// Removes expired pending server IDs and sessions, persists sessions and syncs revocations. Called periodically from main
+pub async fn sweep(state: &AppState) {
+    let pending_ttl = Duration::from_secs(state.config.auth.pending_ttl);
+    let pending = state.pending.lock().await;
+    let before = pending.len();
+    pending.retain(|_, (_, created)| created.elapsed() < pending_ttl);
+    let pending_removed = before - pending.len();
+    drop(pending);
+
+    let sessions_removed = state.authenticated.retain(&|userinfo| !is_expired(state, userinfo)).await;
+    state.revocations.sweep(session_ttl(state));
+    for uuid in state.revocations.sync(session_ttl(state)).await {
+        close_connection(state, uuid, None, SessionMessage::Close(CloseCode::ReAuth, String::from("Session revoked"))).await;
+    }
+    state.id_limiter.sweep();
+    state.verify_limiter.sweep();
+    state.authenticated.flush().await;
+
+    if pending_removed != 0 || sessions_removed != 0 {
+        debug!("[Authorization] Swept {pending_removed} pending IDs and {sessions_removed} sessions");
+    }
+}
+
+
+// Это экстрактор достающий из Заголовка зовущегося токен, соответственно ТОКЕН.
+#[derive(PartialEq, Debug)]
+pub struct Token(pub Option<String>);
+
+impl<S> FromRequestParts<S> for Token
+where
+    S: Send + Sync,
+{
+    type Rejection = StatusCode;
+
+    async fn from_request_parts(parts: &mut Parts, _: &S) -> Result<Self, Self::Rejection> {
+        let token = parts
+            .headers
+            .get("token")
+            .and_then(|value| value.to_str().ok());
+        trace!("[Extractor Token] Data: {token:?}");
+        match token {
+            Some(token) => Ok(Self(Some(token.to_string()))),
+            None => Ok(Self(None)),
+        }
+    }
+}
+// Конец экстрактора
+
+// Extracts the client IP from the socket, or from X-Forwarded-For if the request came through a trusted proxy
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub struct ClientIp(pub IpAddr);
+
+impl FromRequestParts<AppState> for ClientIp {
+    type Rejection = StatusCode;
+
+    async fn from_request_parts(parts: &mut Parts, state: &AppState) -> Result<Self, Self::Rejection> {
+        let peer = parts
+            .extensions
+            .get::<ConnectInfo<SocketAddr>>()
+            .map(|ConnectInfo(addr)| addr.ip())
+            .ok_or(StatusCode::INTERNAL_SERVER_ERROR)?;
+        let ip = client_ip(peer, &parts.headers, &state.config.auth.trusted_proxies);
+        trace!("[Extractor ClientIp] Peer: {peer}, client: {ip}");
+        Ok(Self(ip))
+    }
+}
+
+fn client_ip(peer: IpAddr, headers: &HeaderMap, trusted: &[IpAddr]) -> IpAddr {
+    if !trusted.contains(&peer) {
+        return peer;
+    }
+    // Every proxy appends the address it got the request from, so walk from the right
+    // and take the first address which isn't one of our proxies
+    let forwarded: Vec<&str> = headers
+        .get_all("x-forwarded-for")
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .collect();
+    let mut ip = peer;
+    for addr in forwarded.into_iter().rev() {
+        match addr.trim().parse::<IpAddr>() {
+            Ok(addr) if trusted.contains(&addr) => ip = addr,
+            Ok(addr) => {
+                ip = addr;
+                break;
+            },
+            Err(_) => break,
+        }
+    }
+    ip
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::*;
+
+    /// Steve from Mojang, logged in just now
+    pub(super) fn steve() -> Userinfo {
+        Userinfo {
+            username: String::from("Steve"),
+            uuid: Uuid::from_u128(1),
+            auth_system: String::from("Mojang"),
+            created: Utc::now(),
+            ip: None,
+        }
+    }
+
+    /// File in the temp directory which doesn't exist yet and is removed when the test ends
+    pub(super) struct TempFile(pub(super) PathBuf);
+
+    impl TempFile {
+        pub(super) fn new(name: &str) -> Self {
+            Self(std::env::temp_dir().join(format!("sculptor-{name}-{}.json", Uuid::from_u128(rand::random()))))
+        }
+    }
+
+    impl Drop for TempFile {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.0);
+        }
+    }
+
+    fn forwarded(value: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert("x-forwarded-for", value.parse().unwrap());
+        headers
+    }
+
+    #[test]
+    fn client_ip_from_trusted_proxies() {
+        let ip = |s: &str| s.parse::<IpAddr>().unwrap();
+        let proxies = [ip("10.0.0.1"), ip("10.0.0.2")];
+
+        // The client may put anything on the left, only what our proxy appended counts
+        assert_eq!(client_ip(ip("10.0.0.1"), &forwarded("1.1.1.1, 2.2.2.2"), &proxies), ip("2.2.2.2"));
+        // Proxies in a chain are skipped
+        assert_eq!(client_ip(ip("10.0.0.1"), &forwarded("2.2.2.2, 10.0.0.2"), &proxies), ip("2.2.2.2"));
+        // Untrusted peers can't pretend to be somebody else
+        assert_eq!(client_ip(ip("3.3.3.3"), &forwarded("2.2.2.2"), &proxies), ip("3.3.3.3"));
+        // Every hop is ours, so the leftmost one is the closest to the client we know
+        assert_eq!(client_ip(ip("10.0.0.1"), &forwarded("10.0.0.2"), &proxies), ip("10.0.0.2"));
+        // Nothing to the left of garbage can be trusted
+        assert_eq!(client_ip(ip("10.0.0.1"), &forwarded("1.1.1.1, nonsense, 10.0.0.2"), &proxies), ip("10.0.0.2"));
+        assert_eq!(client_ip(ip("10.0.0.1"), &HeaderMap::new(), &proxies), ip("10.0.0.1"));
+    }
+
+    #[tokio::test]
+    async fn ip_bound_token_is_refused_elsewhere() {
+        let config: crate::config::Config = toml::from_str(r#"
+            listen = "127.0.0.1:0"
+            motd = ""
+            advancedUsers = {}
+        "#).unwrap();
+        let state = AppState::new(config).unwrap();
+        let issued_to = "1.1.1.1".parse().unwrap();
+        let userinfo = Userinfo { ip: Some(issued_to), ..steve() };
+        state.authenticated.insert(String::from("token"), userinfo).await;
+        assert!(check_session(&state, "token", issued_to).await.is_ok());
+        assert_eq!(check_session(&state, "token", "2.2.2.2".parse().unwrap()).await.unwrap_err(), SessionError::WrongIp);
+    }
+}
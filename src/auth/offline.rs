@@ -0,0 +1,43 @@
+use std::net::IpAddr;
+
+use async_trait::async_trait;
+use md5::{Digest, Md5};
+use uuid::{Builder, Uuid};
+
+use super::{provider::AuthProvider, AuthError};
+
+/// Accepts any username without asking a session server, for local development.
+/// Players get the same UUID as on an offline-mode Minecraft server.
+#[derive(Debug, Clone)]
+pub struct Offline {
+    name: String,
+}
+
+impl Offline {
+    pub fn new(name: String) -> Self {
+        Self { name }
+    }
+}
+
+/// UUID v3 of "OfflinePlayer:<username>", like the vanilla server does
+pub fn offline_uuid(username: &str) -> Uuid {
+    let hash = Md5::digest(format!("OfflinePlayer:{username}"));
+    Builder::from_md5_bytes(hash.into()).into_uuid()
+}
+
+#[async_trait]
+impl AuthProvider for Offline {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    async fn has_joined(&self, _: &reqwest::Client, _: &str, username: &str, _: Option<IpAddr>) -> Result<Option<Uuid>, AuthError> {
+        Ok(Some(offline_uuid(username)))
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn vanilla_offline_uuid() {
+    assert_eq!(offline_uuid("Notch"), Uuid::parse_str("b50ad385-829d-3141-a216-7e7d7539ba7f").unwrap());
+}
@@ -0,0 +1,283 @@
+use std::{cmp::Reverse, fmt::Debug, net::IpAddr, sync::Arc, time::Duration};
+
+use async_trait::async_trait;
+use log::{debug, info, warn};
+use percent_encoding::{utf8_percent_encode, NON_ALPHANUMERIC};
+use tokio::task::JoinSet;
+use uuid::Uuid;
+
+use crate::config::{AuthConfig, ProviderKind};
+
+use super::{breaker::CircuitBreaker, AuthError};
+
+/// Session server that can confirm a player has joined with the given serverId.
+#[async_trait]
+pub trait AuthProvider: Debug + Send + Sync {
+    /// Name from the config. It is stored in `Userinfo.auth_system` and shown in logs.
+    fn name(&self) -> &str;
+    /// `Ok(None)` means the provider answered, but the player hasn't joined.
+    /// `client` is shared between all providers, see `AppState.http_client`.
+    /// If `ip` is passed, the provider should also check that the player joined from it.
+    async fn has_joined(&self, client: &reqwest::Client, server_id: &str, username: &str, ip: Option<IpAddr>) -> Result<Option<Uuid>, AuthError>;
+}
+
+/// Any Yggdrasil-compatible session server (Mojang, Ely.by, Drasl, Blessing Skin, ...)
+#[derive(Debug, Clone)]
+pub struct Yggdrasil {
+    name: String,
+    url: String, // hasJoined URL template with {serverId} and {username} placeholders
+}
+
+pub const MOJANG_URL: &str = "https://sessionserver.mojang.com/session/minecraft/hasJoined?serverId={serverId}&username={username}";
+pub const ELYBY_URL: &str = "http://minecraft.ely.by/session/hasJoined?serverId={serverId}&username={username}";
+
+impl Yggdrasil {
+    pub fn new(name: String, url: String) -> Self {
+        Self { name, url }
+    }
+
+    fn url(&self, server_id: &str, username: &str, ip: Option<IpAddr>) -> String {
+        let url = self.url
+            .replace("{serverId}", server_id)
+            .replace("{username}", &utf8_percent_encode(username, NON_ALPHANUMERIC).to_string());
+        match ip {
+            Some(ip) => {
+                let separator = if url.contains('?') { '&' } else { '?' };
+                format!("{url}{separator}ip={ip}")
+            },
+            None => url,
+        }
+    }
+}
+
+#[async_trait]
+impl AuthProvider for Yggdrasil {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    async fn has_joined(&self, client: &reqwest::Client, server_id: &str, username: &str, ip: Option<IpAddr>) -> Result<Option<Uuid>, AuthError> {
+        let unavailable = |e: reqwest::Error| AuthError::ProviderUnavailable(self.name.clone(), e.to_string());
+        let malformed = |reason: String| AuthError::MalformedProviderResponse(self.name.clone(), reason);
+
+        let res = client.get(self.url(server_id, username, ip)).send().await.map_err(unavailable)?;
+        debug!("[Auth {}] {res:?}", self.name);
+        match res.status().as_u16() {
+            200 => {
+                let text = res.text().await.map_err(unavailable)?;
+                let json = serde_json::from_str::<serde_json::Value>(&text).map_err(|e| malformed(e.to_string()))?;
+                let id = json["id"].as_str().ok_or_else(|| malformed(String::from("no id field")))?;
+                Uuid::parse_str(id).map(Some).map_err(|e| malformed(e.to_string()))
+            },
+            // Mojang answers 204, Ely.by and most authlib-injector servers answer 401
+            204 | 401 | 403 => Ok(None),
+            code => Err(AuthError::ProviderUnavailable(self.name.clone(), format!("unknown code {code}"))),
+        }
+    }
+}
+
+/// Provider registered from the config
+#[derive(Debug, Clone)]
+struct Registered {
+    provider: Arc<dyn AuthProvider>,
+    priority: i32,
+    timeout: Duration,
+    retries: u32,
+    breaker: Arc<CircuitBreaker>,
+}
+
+impl Registered {
+    /// Asks the provider with timeout and retries, unless its circuit breaker is open
+    async fn has_joined(&self, client: &reqwest::Client, server_id: &str, username: &str, ip: Option<IpAddr>) -> Result<Option<Uuid>, AuthError> {
+        let name = self.provider.name();
+        let mut attempt = 0;
+        loop {
+            let Some(breaker) = self.breaker.allow() else {
+                return Err(AuthError::ProviderUnavailable(name.to_string(), String::from("circuit breaker is open")));
+            };
+            let res = match tokio::time::timeout(self.timeout, self.provider.has_joined(client, server_id, username, ip)).await {
+                Ok(res) => res,
+                Err(_) => Err(AuthError::ProviderUnavailable(name.to_string(), format!("timed out after {:?}", self.timeout))),
+            };
+            match res {
+                Err(AuthError::ProviderUnavailable(_, reason)) if attempt < self.retries => {
+                    breaker.failure();
+                    attempt += 1;
+                    debug!("[Auth {name}] Retrying ({attempt}/{}) after: {reason}", self.retries);
+                    tokio::time::sleep(RETRY_DELAY * attempt).await;
+                },
+                Err(e) => {
+                    breaker.failure();
+                    return Err(e);
+                },
+                Ok(res) => {
+                    breaker.success();
+                    return Ok(res);
+                },
+            }
+        }
+    }
+}
+
+const RETRY_DELAY: Duration = Duration::from_millis(250);
+
+/// Providers registered from the config, sorted by priority
+#[derive(Debug, Clone)]
+pub struct AuthProviders(Vec<Registered>);
+
+impl AuthProviders {
+    pub fn from_config(config: &AuthConfig) -> Self {
+        let mut registered: Vec<Registered> = config.providers.iter().filter(|provider| {
+            if !provider.enabled {
+                info!("[Auth] Provider {} is disabled", provider.name);
+            }
+            provider.enabled
+        }).map(|provider| {
+            let registered: Arc<dyn AuthProvider> = match (&provider.url, provider.kind) {
+                #[cfg(feature = "dev-auth")]
+                (_, ProviderKind::Offline) => {
+                    warn!("[Auth] Provider {} is offline, anyone can log in with any username!", provider.name);
+                    Arc::new(super::offline::Offline::new(provider.name.clone()))
+                },
+                (Some(url), _) => Arc::new(Yggdrasil::new(provider.name.clone(), url.clone())),
+                (None, ProviderKind::Mojang) => Arc::new(Yggdrasil::new(provider.name.clone(), MOJANG_URL.to_string())),
+                (None, ProviderKind::ElyBy) => Arc::new(Yggdrasil::new(provider.name.clone(), ELYBY_URL.to_string())),
+                (None, ProviderKind::Yggdrasil) => panic!("Provider {} requires url!", provider.name),
+            };
+            Registered {
+                provider: registered,
+                priority: provider.priority,
+                timeout: Duration::from_secs(provider.timeout),
+                retries: provider.retries,
+                breaker: Arc::new(CircuitBreaker::new(
+                    provider.name.clone(),
+                    config.breaker_threshold,
+                    Duration::from_secs(config.breaker_cooldown),
+                )),
+            }
+        }).collect();
+        registered.sort_by_key(|r| Reverse(r.priority));
+        if registered.is_empty() {
+            warn!("[Auth] No enabled providers! Nobody will be able to log in");
+        }
+        Self(registered)
+    }
+
+    /// Asks every provider at once. If several of them know the player, the one with the highest priority wins,
+    /// but we don't wait for providers with a lower priority than the one which already answered.
+    /// Returns `NotJoined` if nobody knows the player, or the error of the most prioritized provider if all of them failed.
+    pub async fn has_joined(&self, client: &reqwest::Client, server_id: &str, username: &str, ip: Option<IpAddr>) -> Result<(Uuid, String), AuthError> {
+        let mut requests = JoinSet::new();
+        for (index, registered) in self.0.iter().enumerate() {
+            let (registered, client) = (registered.clone(), client.clone());
+            let (server_id, username) = (server_id.to_string(), username.to_string());
+            requests.spawn(async move {
+                (index, registered.has_joined(&client, &server_id, &username, ip).await)
+            });
+        }
+        let mut results: Vec<Option<Result<Option<Uuid>, AuthError>>> = self.0.iter().map(|_| None).collect();
+        while let Some(joined) = requests.join_next().await {
+            let (index, res) = match joined {
+                Ok(joined) => joined,
+                Err(e) => {
+                    // Task panicked, we can't tell which provider it was
+                    warn!("[Auth] Request task failed: {e}");
+                    continue;
+                }
+            };
+            if let Err(e) = &res {
+                warn!("[Auth] Request failed: {e}");
+            }
+            results[index] = Some(res);
+            // Walk from the highest priority until we meet a provider which hasn't answered yet
+            for (index, res) in results.iter().enumerate() {
+                match res {
+                    None => break,
+                    Some(Ok(Some(uuid))) => return Ok((*uuid, self.0[index].provider.name().to_string())),
+                    Some(_) => (),
+                }
+            }
+        }
+        let mut errors = results.into_iter().map(|res| match res {
+            Some(Err(e)) => Some(e),
+            _ => None,
+        });
+        match errors.next() {
+            Some(Some(first)) if errors.all(|e| e.is_some()) => Err(first),
+            _ => Err(AuthError::NotJoined),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Debug)]
+    struct Fake(&'static str, Duration, Option<Uuid>);
+
+    #[async_trait]
+    impl AuthProvider for Fake {
+        fn name(&self) -> &str {
+            self.0
+        }
+
+        async fn has_joined(&self, _: &reqwest::Client, _: &str, _: &str, _: Option<IpAddr>) -> Result<Option<Uuid>, AuthError> {
+            tokio::time::sleep(self.1).await;
+            Ok(self.2)
+        }
+    }
+
+    fn registered(fake: Fake, priority: i32) -> Registered {
+        let breaker = Arc::new(CircuitBreaker::new(fake.0.to_string(), 5, Duration::from_secs(60)));
+        Registered { provider: Arc::new(fake), priority, timeout: Duration::from_secs(1), retries: 0, breaker }
+    }
+
+    #[test]
+    fn username_is_encoded() {
+        let mojang = Yggdrasil::new(String::from("Mojang"), String::from(MOJANG_URL));
+        assert_eq!(
+            mojang.url("abc", "Jöhn&x=1", None),
+            "https://sessionserver.mojang.com/session/minecraft/hasJoined?serverId=abc&username=J%C3%B6hn%26x%3D1"
+        );
+    }
+
+    #[test]
+    fn ip_starts_query_if_needed() {
+        let ip = Some("1.2.3.4".parse().unwrap());
+        let mojang = Yggdrasil::new(String::from("Mojang"), String::from(MOJANG_URL));
+        assert!(mojang.url("abc", "Steve", ip).ends_with("&username=Steve&ip=1.2.3.4"));
+        let path = Yggdrasil::new(String::from("Custom"), String::from("https://example.com/hasJoined/{serverId}/{username}"));
+        assert_eq!(path.url("abc", "Steve", ip), "https://example.com/hasJoined/abc/Steve?ip=1.2.3.4");
+    }
+
+    #[tokio::test]
+    async fn higher_priority_wins() {
+        let (slow, fast) = (Uuid::from_u128(1), Uuid::from_u128(2));
+        let providers = AuthProviders(vec![
+            registered(Fake("slow", Duration::from_millis(50), Some(slow)), 1),
+            registered(Fake("fast", Duration::ZERO, Some(fast)), 0),
+        ]);
+        assert_eq!(providers.has_joined(&reqwest::Client::new(), "", "", None).await, Ok((slow, String::from("slow"))));
+
+        let providers = AuthProviders(vec![
+            registered(Fake("slow", Duration::from_millis(50), None), 1),
+            registered(Fake("fast", Duration::ZERO, Some(fast)), 0),
+        ]);
+        assert_eq!(providers.has_joined(&reqwest::Client::new(), "", "", None).await, Ok((fast, String::from("fast"))));
+    }
+
+    #[tokio::test]
+    async fn cancelled_check_reopens_breaker() {
+        let uuid = Uuid::from_u128(1);
+        let breaker = Arc::new(CircuitBreaker::new(String::from("recovering"), 1, Duration::ZERO));
+        let recovering = Registered { breaker: breaker.clone(), ..registered(Fake("recovering", Duration::from_millis(100), None), 0) };
+        // Open, but the cooldown is over, so the next request checks the provider
+        breaker.allow().unwrap().failure();
+        let providers = AuthProviders(vec![registered(Fake("primary", Duration::from_millis(10), Some(uuid)), 1), recovering]);
+        // The primary answers while the recovering provider is being checked, so the check is aborted
+        assert_eq!(providers.has_joined(&reqwest::Client::new(), "", "", None).await, Ok((uuid, String::from("primary"))));
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        assert!(breaker.allow().is_some());
+    }
+}
@@ -0,0 +1,144 @@
+use std::{collections::HashMap, path::PathBuf};
+
+use chrono::{DateTime, Utc};
+use dashmap::DashMap;
+use log::{error, warn};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::Userinfo;
+
+use super::session::session_id;
+
+/// Revoked signed tokens. Stored tokens are simply removed from the SessionStore, but signed ones
+/// are valid by themselves, so what was revoked is remembered until it expires.
+/// With `revocationFile` the instances share it through the file, see `sync`.
+#[derive(Debug, Default)]
+pub struct Revocations {
+    tokens: DashMap<String, DateTime<Utc>>, // <session_id of the token, expires>
+    users: DashMap<Uuid, DateTime<Utc>>, // <UUID, tokens created before are revoked>
+    path: Option<PathBuf>,
+}
+
+/// Contents of the revocation file
+#[derive(Serialize, Deserialize, Default, PartialEq, Debug)]
+struct Snapshot {
+    tokens: HashMap<String, DateTime<Utc>>,
+    users: HashMap<Uuid, DateTime<Utc>>,
+}
+
+impl Revocations {
+    pub fn open(path: Option<PathBuf>) -> Self {
+        Self { path, ..Default::default() }
+    }
+
+    pub fn revoke_token(&self, token: &str, expires: DateTime<Utc>) {
+        self.tokens.insert(session_id(token), expires);
+    }
+
+    pub fn revoke_user(&self, uuid: Uuid) {
+        self.users.insert(uuid, Utc::now());
+    }
+
+    pub fn is_revoked(&self, token: &str, userinfo: &Userinfo) -> bool {
+        self.tokens.contains_key(&session_id(token))
+            || self.users.get(&userinfo.uuid).is_some_and(|before| userinfo.created <= *before)
+    }
+
+    /// Forgets revocations of tokens which have expired anyway
+    pub fn sweep(&self, session_ttl: chrono::Duration) {
+        let now = Utc::now();
+        self.tokens.retain(|_, expires| *expires > now);
+        self.users.retain(|_, before| *before + session_ttl > now);
+    }
+
+    /// Takes revocations of other instances from the file and writes ours there if it misses any.
+    /// Returns users revoked by other instances since the last call. Called periodically from `auth::sweep`
+    pub async fn sync(&self, session_ttl: chrono::Duration) -> Vec<Uuid> {
+        let Some(path) = &self.path else {
+            return Vec::new();
+        };
+        let stored = match tokio::fs::read_to_string(path).await {
+            Ok(data) => serde_json::from_str(&data).unwrap_or_else(|e| {
+                warn!("[Revocations] Can't parse {}, rewriting it: {e}", path.display());
+                Snapshot::default()
+            }),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Snapshot::default(),
+            Err(e) => {
+                error!("[Revocations] Can't read {}: {e}", path.display());
+                return Vec::new();
+            },
+        };
+        let now = Utc::now();
+        for (token, expires) in &stored.tokens {
+            if *expires > now {
+                self.tokens.entry(token.clone()).or_insert(*expires);
+            }
+        }
+        let mut revoked = Vec::new();
+        for (uuid, before) in &stored.users {
+            if *before + session_ttl > now && self.users.get(uuid).is_none_or(|ours| *ours < *before) {
+                self.users.insert(*uuid, *before);
+                revoked.push(*uuid);
+            }
+        }
+
+        let snapshot = Snapshot {
+            tokens: self.tokens.iter().map(|entry| (entry.key().clone(), *entry.value())).collect(),
+            users: self.users.iter().map(|entry| (*entry.key(), *entry.value())).collect(),
+        };
+        if snapshot != stored {
+            // Instances may write at the same time, each to its own temporary file. If one of them
+            // overwrites the other's revocations, the other one writes them again on the next sync
+            let tmp = path.with_extension(format!("{:x}.tmp", rand::random::<u64>()));
+            if let Err(e) = async {
+                tokio::fs::write(&tmp, serde_json::to_string(&snapshot)?).await?;
+                tokio::fs::rename(&tmp, path).await
+            }.await {
+                error!("[Revocations] Can't write {}: {e}", path.display());
+            }
+        }
+        revoked
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn revoked_tokens_and_users() {
+    let userinfo = Userinfo { created: Utc::now() - chrono::Duration::minutes(1), ..super::tests::steve() };
+    let revocations = Revocations::default();
+    assert!(!revocations.is_revoked("a.b", &userinfo));
+    revocations.revoke_token("a.b", Utc::now() + chrono::Duration::hours(1));
+    assert!(revocations.is_revoked("a.b", &userinfo));
+    assert!(!revocations.is_revoked("c.d", &userinfo));
+
+    // Tokens issued after logging out everywhere stay valid
+    revocations.revoke_user(userinfo.uuid);
+    assert!(revocations.is_revoked("c.d", &userinfo));
+    let fresh = Userinfo { created: Utc::now() + chrono::Duration::seconds(1), ..userinfo };
+    assert!(!revocations.is_revoked("c.d", &fresh));
+}
+
+#[cfg(test)]
+#[tokio::test]
+async fn instances_share_revocations() {
+    let file = super::tests::TempFile::new("revocations");
+    let path = &file.0;
+    let ttl = chrono::Duration::hours(1);
+    let userinfo = Userinfo { created: Utc::now() - chrono::Duration::minutes(1), ..super::tests::steve() };
+    let (first, second) = (Revocations::open(Some(path.clone())), Revocations::open(Some(path.clone())));
+    first.revoke_token("a.b", Utc::now() + ttl);
+    assert!(first.sync(ttl).await.is_empty());
+    assert!(second.sync(ttl).await.is_empty());
+    assert!(second.is_revoked("a.b", &userinfo));
+
+    second.revoke_user(userinfo.uuid);
+    second.sync(ttl).await;
+    assert_eq!(first.sync(ttl).await, vec![userinfo.uuid]);
+    assert!(first.is_revoked("c.d", &userinfo));
+    // Already known
+    assert!(first.sync(ttl).await.is_empty());
+
+    // Tokens themselves aren't written
+    assert!(!std::fs::read_to_string(path).unwrap().contains("a.b"));
+}
@@ -0,0 +1,192 @@
+use std::{collections::HashMap, fmt::Debug, path::PathBuf, sync::{atomic::{AtomicBool, Ordering}, Arc}};
+
+use async_trait::async_trait;
+use dashmap::DashMap;
+use log::{error, info, warn};
+use ring::digest::{self, digest};
+use tokio::sync::Mutex;
+use uuid::Uuid;
+
+use crate::{config::{AuthConfig, SessionStoreKind}, Userinfo};
+
+/// Storage of authenticated users. `verify` writes to it and token lookups read from it.
+/// Sessions are kept by `session_id` of the token, not by the token itself.
+#[async_trait]
+pub trait SessionStore: Debug + Send + Sync {
+    async fn get(&self, token: &str) -> Option<Userinfo>;
+    async fn insert(&self, token: String, userinfo: Userinfo);
+    async fn remove(&self, token: &str) -> Option<Userinfo>;
+    /// All sessions of the user as (session ID, Userinfo)
+    async fn find(&self, uuid: Uuid) -> Vec<(String, Userinfo)>;
+    /// Keeps only sessions for which `keep` returns true. Returns the number of removed sessions
+    async fn retain(&self, keep: &(dyn for<'a> Fn(&'a Userinfo) -> bool + Send + Sync)) -> usize;
+    /// Persists changes, if the store does it at all. Called periodically from the sweeper and on shutdown
+    async fn flush(&self) {}
+}
+
+/// SHA-256 of the token in hex, so a leaked store doesn't give away live tokens
+pub fn session_id(token: &str) -> String {
+    hex::encode(digest(&digest::SHA256, token.as_bytes()))
+}
+
+pub fn from_config(config: &AuthConfig) -> Arc<dyn SessionStore> {
+    match config.session_store {
+        SessionStoreKind::Memory => Arc::<MemoryStore>::default(),
+        SessionStoreKind::File => Arc::new(FileStore::open(config.session_file.clone())),
+    }
+}
+
+/// Sessions live only while the server is running
+#[derive(Debug, Default)]
+pub struct MemoryStore(DashMap<String, Userinfo>); // <session ID, Userinfo>
+
+#[async_trait]
+impl SessionStore for MemoryStore {
+    async fn get(&self, token: &str) -> Option<Userinfo> {
+        self.0.get(&session_id(token)).map(|userinfo| userinfo.clone())
+    }
+
+    async fn insert(&self, token: String, userinfo: Userinfo) {
+        self.0.insert(session_id(&token), userinfo);
+    }
+
+    async fn remove(&self, token: &str) -> Option<Userinfo> {
+        self.0.remove(&session_id(token)).map(|(_, userinfo)| userinfo)
+    }
+
+    async fn find(&self, uuid: Uuid) -> Vec<(String, Userinfo)> {
+        self.0.iter()
+            .filter(|entry| entry.value().uuid == uuid)
+            .map(|entry| (entry.key().clone(), entry.value().clone()))
+            .collect()
+    }
+
+    async fn retain(&self, keep: &(dyn for<'a> Fn(&'a Userinfo) -> bool + Send + Sync)) -> usize {
+        let before = self.0.len();
+        self.0.retain(|_, userinfo| keep(userinfo));
+        before - self.0.len()
+    }
+}
+
+/// Sessions are kept in memory and written to a JSON file on flush, so they survive restarts
+#[derive(Debug)]
+pub struct FileStore {
+    path: PathBuf,
+    sessions: MemoryStore,
+    dirty: AtomicBool, // Changed since the last flush
+    write: Mutex<()>, // Serializes writes to the file
+}
+
+impl FileStore {
+    pub fn open(path: PathBuf) -> Self {
+        let sessions = MemoryStore::default();
+        match std::fs::read_to_string(&path) {
+            Ok(data) => match serde_json::from_str::<HashMap<String, Userinfo>>(&data) {
+                Ok(loaded) => {
+                    info!("[Sessions] Loaded {} sessions from {}", loaded.len(), path.display());
+                    for (id, userinfo) in loaded {
+                        sessions.0.insert(id, userinfo);
+                    }
+                },
+                Err(e) => warn!("[Sessions] Can't parse {}, starting without sessions: {e}", path.display()),
+            },
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => (),
+            Err(e) => warn!("[Sessions] Can't read {}, starting without sessions: {e}", path.display()),
+        }
+        Self { path, sessions, dirty: AtomicBool::new(false), write: Mutex::new(()) }
+    }
+
+    fn changed(&self) {
+        self.dirty.store(true, Ordering::Relaxed);
+    }
+
+    async fn save(&self) {
+        let _guard = self.write.lock().await;
+        // Changes made from now on will be in the next flush
+        self.dirty.store(false, Ordering::Relaxed);
+        let snapshot: HashMap<String, Userinfo> = self.sessions.0.iter()
+            .map(|entry| (entry.key().clone(), entry.value().clone()))
+            .collect();
+        let data = match serde_json::to_string(&snapshot) {
+            Ok(data) => data,
+            Err(e) => {
+                error!("[Sessions] Can't serialize sessions: {e}");
+                return;
+            }
+        };
+        // Write to a temporary file first, so a crash doesn't leave a half-written file
+        let tmp = self.path.with_extension("tmp");
+        if let Err(e) = async {
+            tokio::fs::write(&tmp, data).await?;
+            tokio::fs::rename(&tmp, &self.path).await
+        }.await {
+            error!("[Sessions] Can't write {}: {e}", self.path.display());
+        }
+    }
+}
+
+#[async_trait]
+impl SessionStore for FileStore {
+    async fn get(&self, token: &str) -> Option<Userinfo> {
+        self.sessions.get(token).await
+    }
+
+    async fn insert(&self, token: String, userinfo: Userinfo) {
+        self.sessions.insert(token, userinfo).await;
+        self.changed();
+    }
+
+    async fn remove(&self, token: &str) -> Option<Userinfo> {
+        let removed = self.sessions.remove(token).await;
+        if removed.is_some() {
+            self.changed();
+        }
+        removed
+    }
+
+    async fn find(&self, uuid: Uuid) -> Vec<(String, Userinfo)> {
+        self.sessions.find(uuid).await
+    }
+
+    async fn retain(&self, keep: &(dyn for<'a> Fn(&'a Userinfo) -> bool + Send + Sync)) -> usize {
+        let removed = self.sessions.retain(keep).await;
+        if removed != 0 {
+            self.changed();
+        }
+        removed
+    }
+
+    async fn flush(&self) {
+        if self.dirty.load(Ordering::Relaxed) {
+            self.save().await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::tests::{steve, TempFile};
+
+    #[tokio::test]
+    async fn file_store_survives_restart() {
+        let file = TempFile::new("sessions");
+        let path = &file.0;
+        let userinfo = steve();
+
+        let store = FileStore::open(path.clone());
+        store.insert(String::from("token"), userinfo.clone()).await;
+        store.insert(String::from("other"), userinfo.clone()).await;
+        store.remove("other").await;
+        // Nothing is written until flush
+        assert!(!path.exists());
+        store.flush().await;
+        drop(store);
+
+        // Only hashes of the tokens are stored
+        assert!(!std::fs::read_to_string(path).unwrap().contains("token"));
+        let store = FileStore::open(path.clone());
+        assert_eq!(store.get("token").await.map(|u| u.uuid), Some(userinfo.uuid));
+        assert!(store.get("other").await.is_none());
+    }
+}
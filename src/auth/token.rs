@@ -0,0 +1,79 @@
+use base64::prelude::*;
+use chrono::{DateTime, Utc};
+use log::{debug, warn};
+use ring::hmac;
+use serde::{Deserialize, Serialize};
+
+use crate::Userinfo;
+
+use super::SessionError;
+
+/// Issues and checks stateless tokens: `base64url(claims).base64url(HMAC-SHA256(claims))`.
+/// Any instance holding the same secret can validate them without shared state.
+#[derive(Debug, Clone)]
+pub struct TokenSigner(hmac::Key);
+
+#[derive(Serialize, Deserialize)]
+struct Claims {
+    #[serde(flatten)]
+    userinfo: Userinfo,
+    expires: DateTime<Utc>,
+}
+
+impl TokenSigner {
+    pub fn new(secret: &str) -> Self {
+        if secret.len() < 32 {
+            warn!("[Token] tokenSecret is shorter than 32 characters, tokens may be forged!");
+        }
+        Self(hmac::Key::new(hmac::HMAC_SHA256, secret.as_bytes()))
+    }
+
+    /// Tokens issued by the old way are hex strings, so they never contain a dot
+    pub fn is_signed(token: &str) -> bool {
+        token.contains('.')
+    }
+
+    pub fn sign(&self, userinfo: &Userinfo, expires: DateTime<Utc>) -> String {
+        let claims = serde_json::to_vec(&Claims { userinfo: userinfo.clone(), expires })
+            .expect("Userinfo is always serializable");
+        let signature = hmac::sign(&self.0, &claims);
+        format!("{}.{}", BASE64_URL_SAFE_NO_PAD.encode(claims), BASE64_URL_SAFE_NO_PAD.encode(signature))
+    }
+
+    /// Returns the user if the signature is valid and the token hasn't expired yet
+    pub fn verify(&self, token: &str) -> Result<Userinfo, SessionError> {
+        let (claims, signature) = token.split_once('.').ok_or(SessionError::Unknown)?;
+        let claims = BASE64_URL_SAFE_NO_PAD.decode(claims).map_err(|_| SessionError::Unknown)?;
+        let signature = BASE64_URL_SAFE_NO_PAD.decode(signature).map_err(|_| SessionError::Unknown)?;
+        if hmac::verify(&self.0, &claims, &signature).is_err() {
+            warn!("[Token] Token with invalid signature!");
+            return Err(SessionError::Unknown);
+        }
+        let claims: Claims = serde_json::from_slice(&claims).map_err(|_| SessionError::Unknown)?;
+        if claims.expires < Utc::now() {
+            debug!("[Token] Token of {} has expired", claims.userinfo.username);
+            return Err(SessionError::Expired);
+        }
+        Ok(claims.userinfo)
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn signed_token_round_trip() {
+    let userinfo = super::tests::steve();
+    let signer = TokenSigner::new("0123456789abcdef0123456789abcdef");
+    let token = signer.sign(&userinfo, Utc::now() + chrono::Duration::hours(1));
+    assert!(TokenSigner::is_signed(&token));
+    assert_eq!(signer.verify(&token).map(|u| u.uuid), Ok(userinfo.uuid));
+
+    // Another secret or a changed payload
+    assert_eq!(TokenSigner::new("another secret").verify(&token).map(|u| u.uuid), Err(SessionError::Unknown));
+    let (claims, signature) = token.split_once('.').unwrap();
+    let forged = format!("{}A.{signature}", claims);
+    assert_eq!(signer.verify(&forged).map(|u| u.uuid), Err(SessionError::Unknown));
+
+    // Expired
+    let token = signer.sign(&userinfo, Utc::now() - chrono::Duration::hours(1));
+    assert_eq!(signer.verify(&token).map(|u| u.uuid), Err(SessionError::Expired));
+}
@@ -1,23 +1,292 @@
-use std::{io::Read, path::PathBuf};
+use std::{collections::HashMap, net::IpAddr, path::PathBuf};
 
+use chrono::{DateTime, Utc};
 use serde::Deserialize;
 use toml::Table;
+use uuid::Uuid;
+
+use crate::announcements::Cron;
 
 #[derive(Deserialize, Clone, Debug)]
 #[serde(rename_all = "camelCase")]
 pub struct Config {
     pub listen: String,
     pub motd: String,
+    #[serde(default)]
+    pub auth: AuthConfig,
     pub advanced_users: Table,
+    #[serde(default)]
+    pub banned_users: HashMap<Uuid, Ban>,
+    #[serde(default)]
+    pub whitelist: Whitelist,
+    #[serde(default)]
+    pub admin: AdminConfig,
+    #[serde(default)]
+    pub limits: LimitsConfig,
+    #[serde(default)]
+    pub announcements: Vec<Announcement>,
+}
+
+/// Toast sent to online players on schedule, see announcements.rs
+#[derive(Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct Announcement {
+    pub header: String,
+    pub description: Option<String>,
+    #[serde(default)]
+    pub kind: u8, // Toast type
+    #[serde(default, deserialize_with = "deserialize_cron")]
+    pub schedule: Option<Cron>, // Repeats
+    pub at: Option<DateTime<Utc>>, // Once
+    #[serde(default)]
+    pub duration: u64, // Seconds, players who connect during them get it too
+    #[serde(default)]
+    pub audience: Audience,
+}
+
+fn deserialize_cron<'de, D: serde::Deserializer<'de>>(deserializer: D) -> Result<Option<Cron>, D::Error> {
+    Option::<String>::deserialize(deserializer)?
+        .map(|s| Cron::try_from(s).map_err(serde::de::Error::custom))
+        .transpose()
+}
+
+#[derive(Deserialize, Clone, Debug, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Audience {
+    #[default]
+    All,
+    Rank(Rank),
+    Uuids(Vec<Uuid>),
+}
+
+/// Special badges from advancedUsers, in the same order
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Rank {
+    Developer,
+    Staff,
+    Contest,
+    Supporter,
+    Translator,
+    Artist,
+}
+
+/// Limits served by /limits. Ping ones are also enforced by the WebSocket handler
+#[derive(Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase", default)]
+pub struct LimitsConfig {
+    pub ping_size: usize, // Bytes of ping data per second, 0 disables
+    pub ping_rate: u32, // Pings per second, 0 disables
+    pub max_connections: usize, // WebSocket connections of one UUID, 0 disables. Not served by /limits
+    pub connection_overflow: ConnectionOverflow,
+    pub max_lag: u32, // Times per minute a subscriber may fall behind before it's disconnected, 0 disables
+    pub auth_timeout: u64, // Seconds for a new WebSocket connection to send Token
+}
+
+/// What to do with a new connection over maxConnections
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub enum ConnectionOverflow {
+    CloseOldest, // The oldest connection is closed with 4000
+    Refuse, // The new connection is closed with 4002
+}
+
+impl Default for LimitsConfig {
+    fn default() -> Self {
+        Self {
+            ping_size: 1024,
+            ping_rate: 32,
+            max_connections: 2,
+            connection_overflow: ConnectionOverflow::CloseOldest,
+            max_lag: 5,
+            auth_timeout: 10,
+        }
+    }
 }
 
+#[derive(Deserialize, Clone, Debug, Default)]
+#[serde(rename_all = "camelCase", default)]
+pub struct AdminConfig {
+    pub token: Option<String>, // Admin API is disabled if not set
+}
+
+/// Private server mode: only listed users may log in
+#[derive(Deserialize, Clone, Debug, PartialEq, Eq, Default)]
+#[serde(rename_all = "camelCase", default)]
+pub struct Whitelist {
+    pub enabled: bool,
+    pub users: Vec<String>, // UUIDs or usernames with their provider, e.g. "Mojang:Steve"
+    pub providers: Vec<String>, // Names of providers whose users are all allowed
+}
+
+impl Whitelist {
+    pub fn allows(&self, uuid: Uuid, username: &str, provider: &str) -> bool {
+        !self.enabled
+            || self.providers.iter().any(|p| p == provider)
+            || self.users.iter().any(|user| match Uuid::parse_str(user) {
+                Ok(u) => u == uuid,
+                // Another provider may have a different player with the same name
+                Err(_) => user.split_once(':').is_some_and(|(p, name)| p == provider && name.eq_ignore_ascii_case(username)),
+            })
+    }
+}
+
+#[derive(Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct Ban {
+    pub reason: String,
+    pub expires: Option<DateTime<Utc>>, // Permanent if not set
+}
+
+impl Ban {
+    pub fn is_active(&self) -> bool {
+        self.expires.is_none_or(|expires| expires > Utc::now())
+    }
+}
+
+#[derive(Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase", default)]
+pub struct AuthConfig {
+    pub providers: Vec<ProviderConfig>,
+    pub pending_ttl: u64, // Seconds
+    pub max_pending: usize, // Outstanding serverIds from /auth/id in total
+    pub max_pending_per_username: usize,
+    pub id_rate_limit: u32, // Requests to /auth/id per minute from one IP, 0 disables
+    pub verify_rate_limit: u32, // Requests to /auth/verify per minute from one IP, 0 disables
+    pub session_ttl: u64, // Hours
+    pub session_store: SessionStoreKind,
+    pub session_file: PathBuf, // Only for "file" store
+    pub breaker_threshold: u32, // Failures in a row before the provider is skipped
+    pub breaker_cooldown: u64, // Seconds
+    pub verify_ip: bool,
+    pub trusted_proxies: Vec<IpAddr>, // X-Forwarded-For is used only from these addresses
+    pub collision_policy: CollisionPolicy,
+    pub uuid_owners_file: PathBuf, // Only for "reject" and "prefer" policies
+    pub token_secret: Option<String>, // Issue signed stateless tokens if set
+    pub revocation_file: Option<PathBuf>, // Revoked signed tokens shared by the instances, required with token_secret
+}
+
+/// What to do when the same UUID comes from different providers
+#[derive(Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum CollisionPolicy {
+    Shared, // Storage is shared, as if it was the same player
+    Reject, // The provider who logged in with the UUID first owns it
+    Prefer(String), // The provider owns the UUID, others may use it only if it doesn't
+    Namespace(String), // Every provider except this one gets its own storage
+}
+
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum SessionStoreKind {
+    Memory,
+    File,
+}
+
+impl Default for AuthConfig {
+    fn default() -> Self {
+        Self {
+            providers: default_providers(),
+            pending_ttl: 60,
+            max_pending: 10000,
+            max_pending_per_username: 3,
+            id_rate_limit: 20,
+            verify_rate_limit: 20,
+            session_ttl: 24,
+            session_store: SessionStoreKind::Memory,
+            session_file: PathBuf::from("sessions.json"),
+            breaker_threshold: 5,
+            breaker_cooldown: 60,
+            verify_ip: false,
+            trusted_proxies: Vec::new(),
+            collision_policy: CollisionPolicy::Shared,
+            uuid_owners_file: PathBuf::from("owners.json"),
+            token_secret: None,
+            revocation_file: None,
+        }
+    }
+}
+
+#[derive(Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct ProviderConfig {
+    pub name: String,
+    #[serde(rename = "type")]
+    pub kind: ProviderKind,
+    pub url: Option<String>, // hasJoined URL template. Required for "yggdrasil"
+    #[serde(default)]
+    pub priority: i32,
+    #[serde(default = "default_true")]
+    pub enabled: bool,
+    #[serde(default = "default_provider_timeout")]
+    pub timeout: u64, // Seconds
+    #[serde(default = "default_provider_retries")]
+    pub retries: u32,
+}
+
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ProviderKind {
+    Mojang,
+    ElyBy,
+    Yggdrasil,
+    #[cfg(feature = "dev-auth")]
+    Offline, // Accepts anyone, see dev-auth feature
+}
+
+fn default_providers() -> Vec<ProviderConfig> {
+    [("Ely.by", ProviderKind::ElyBy), ("Mojang", ProviderKind::Mojang)].into_iter().map(|(name, kind)| {
+        ProviderConfig {
+            name: String::from(name),
+            kind,
+            url: None,
+            priority: 0,
+            enabled: true,
+            timeout: default_provider_timeout(),
+            retries: default_provider_retries(),
+        }
+    }).collect()
+}
+
+fn default_true() -> bool {
+    true
+}
+
+fn default_provider_timeout() -> u64 {
+    5
+}
+
+fn default_provider_retries() -> u32 {
+    1
+}
 
 impl Config {
     pub fn parse(path: PathBuf) -> Self {
-        let mut file = std::fs::File::open(path).expect("Access denied or file doesn't exists!");
-        let mut data = String::new();
-        file.read_to_string(&mut data).unwrap();
+        Self::load(path).unwrap()
+    }
 
-        toml::from_str(&data).unwrap()
+    /// Like `parse`, but returns the error, for reloading while the server is running
+    pub fn load(path: PathBuf) -> anyhow::Result<Self> {
+        let data = std::fs::read_to_string(path)?;
+        Ok(toml::from_str(&data)?)
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+#[test]
+fn whitelist_allows() {
+    let uuid = Uuid::parse_str("66004548-4de5-49de-bade-9c3933d8eb97").unwrap();
+    let mut whitelist = Whitelist {
+        enabled: true,
+        users: vec![uuid.to_string(), String::from("Mojang:Steve"), String::from("Alex")],
+        providers: vec![String::from("Ely.by")],
+    };
+    assert!(whitelist.allows(uuid, "Shiroyashik", "Mojang"));
+    assert!(whitelist.allows(Uuid::nil(), "steve", "Mojang"));
+    assert!(!whitelist.allows(Uuid::nil(), "Steve", "Offline"));
+    // Usernames without a provider match nobody
+    assert!(!whitelist.allows(Uuid::nil(), "Alex", "Mojang"));
+    assert!(whitelist.allows(Uuid::nil(), "Alex", "Ely.by"));
+    whitelist.enabled = false;
+    assert!(whitelist.allows(Uuid::nil(), "Alex", "Mojang"));
+}
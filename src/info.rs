@@ -1,6 +1,8 @@
-use axum::Json;
+use axum::{extract::State, Json};
 use serde_json::{json, Value};
 
+use crate::AppState;
+
 
 pub async fn version() -> Json<Value> {
     Json(json!({
@@ -9,11 +11,11 @@ pub async fn version() -> Json<Value> {
     }))
 }
 
-pub async fn limits() -> Json<Value> {
+pub async fn limits(State(state): State<AppState>) -> Json<Value> {
     Json(json!({
         "rate": {
-          "pingSize": 1024,
-          "pingRate": 32,
+          "pingSize": state.config.limits.ping_size,
+          "pingRate": state.config.limits.ping_rate,
           "equip": 1,
           "download": 50,
           "upload": 1
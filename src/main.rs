@@ -5,10 +5,11 @@ use axum::{
 use chrono::prelude::*;
 use dashmap::DashMap;
 use fern::colors::{Color, ColoredLevelConfig};
-use log::info;
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
 use uuid::Uuid;
-use std::sync::Arc;
-use tokio::sync::{broadcast, Mutex};
+use std::{collections::HashMap, net::{IpAddr, SocketAddr}, sync::Arc, time::Instant};
+use tokio::sync::{watch, Mutex};
 use tower_http::trace::TraceLayer;
 
 // WebSocket worker
@@ -29,28 +30,101 @@ use profile as api_profile;
 
 // Utils
 mod utils;
+mod ratelimit;
 
 // Config
 mod config;
 
-#[derive(Debug, Clone)]
+// API: Administration
+mod admin;
+
+// Scheduled announcements
+mod announcements;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Userinfo {
     username: String,
     uuid: Uuid,
-    auth_system: api_auth::AuthSystem,
-
+    auth_system: String, // Name of the provider from config
+    created: DateTime<Utc>,
+    #[serde(default)]
+    ip: Option<IpAddr>, // Token is bound to this IP if verifyIp is enabled
 }
 
 #[derive(Debug, Clone)]
 pub struct AppState {
     // Users with incomplete authentication
-    pending: Arc<Mutex<DashMap<String, String>>>, // <SHA1 serverId, USERNAME>
+    pending: Arc<Mutex<DashMap<String, (String, Instant)>>>, // <SHA1 serverId, (USERNAME, created)>
+    // Requests to /auth/id and /auth/verify per IP
+    id_limiter: Arc<ratelimit::RateLimiter<IpAddr>>,
+    verify_limiter: Arc<ratelimit::RateLimiter<IpAddr>>,
     // Authenticated users
-    authenticated: Arc<Mutex<DashMap<String, Userinfo>>>, // <SHA1 serverId, Userinfo> NOTE: In the future, try it in a separate LockRw branch
-    // Ping broadcasts for WebSocket connections
-    broadcasts: Arc<Mutex<DashMap<Uuid, broadcast::Sender<Vec<u8>>>>>,
+    authenticated: Arc<dyn api_auth::SessionStore>, // <session ID, Userinfo>, see session::session_id
+    // Ping broadcasts for WebSocket connections, keyed by storage UUID (see collisionPolicy)
+    broadcasts: Arc<ws::Broadcasts>,
     // Advanced configured users
     advanced_users: Arc<Mutex<toml::Table>>,
+    // Banned users
+    banned_users: Arc<Mutex<HashMap<Uuid, config::Ban>>>,
+    // Users allowed to log in
+    whitelist: Arc<Mutex<config::Whitelist>>,
+    // Scheduled toasts
+    announcements: Arc<Mutex<Vec<config::Announcement>>>,
+    // The same with the last time each of them was delivered, updated by the scheduler every minute
+    delivered_announcements: Arc<Mutex<announcements::Delivered>>,
+    // Live WebSocket connections, used to send messages to a user or to disconnect them
+    user_connections: Arc<Mutex<DashMap<Uuid, Vec<ws::Connection>>>>, // <storage UUID, connections, oldest first>
+    // Session servers from config
+    auth_providers: Arc<api_auth::AuthProviders>,
+    // Signs stateless tokens if tokenSecret is set
+    token_signer: Option<api_auth::TokenSigner>,
+    // Revoked signed tokens
+    revocations: Arc<api_auth::Revocations>,
+    // Providers which own UUIDs, see collisionPolicy
+    uuid_owners: Arc<api_auth::UuidOwners>,
+    // Shared HTTP client for session servers
+    http_client: reqwest::Client,
+    // Config loaded at startup
+    config: Arc<config::Config>,
+    // Set to true when the server is shutting down
+    shutdown: Arc<watch::Sender<bool>>,
+}
+
+impl AppState {
+    fn new(config: config::Config) -> Result<Self> {
+        if let config::CollisionPolicy::Namespace(own) | config::CollisionPolicy::Prefer(own) = &config.auth.collision_policy {
+            if !config.auth.providers.iter().any(|p| &p.name == own) {
+                anyhow::bail!("collisionPolicy refers to {own}, which isn't in auth.providers");
+            }
+        }
+        // Otherwise a revoked token would still work on the other instances
+        if config.auth.token_secret.is_some() && config.auth.revocation_file.is_none() {
+            anyhow::bail!("tokenSecret requires revocationFile");
+        }
+        let http_client = reqwest::Client::builder()
+            .connect_timeout(std::time::Duration::from_secs(5))
+            .build()?;
+        Ok(Self {
+            pending: Arc::new(Mutex::new(DashMap::new())),
+            id_limiter: Arc::new(ratelimit::RateLimiter::per_minute(config.auth.id_rate_limit)),
+            verify_limiter: Arc::new(ratelimit::RateLimiter::per_minute(config.auth.verify_rate_limit)),
+            authenticated: api_auth::session::from_config(&config.auth),
+            broadcasts: Arc::new(ws::Broadcasts::default()),
+            advanced_users: Arc::new(Mutex::new(config.advanced_users.clone())),
+            banned_users: Arc::new(Mutex::new(config.banned_users.clone())),
+            whitelist: Arc::new(Mutex::new(config.whitelist.clone())),
+            announcements: Arc::new(Mutex::new(config.announcements.clone())),
+            delivered_announcements: Arc::new(Mutex::new(Vec::new())),
+            user_connections: Arc::new(Mutex::new(DashMap::new())),
+            auth_providers: Arc::new(api_auth::AuthProviders::from_config(&config.auth)),
+            token_signer: config.auth.token_secret.as_deref().map(api_auth::TokenSigner::new),
+            revocations: Arc::new(api_auth::Revocations::open(config.auth.revocation_file.clone())),
+            uuid_owners: Arc::new(api_auth::UuidOwners::open(config.auth.uuid_owners_file.clone())),
+            http_client,
+            config: Arc::new(config),
+            shutdown: Arc::new(watch::Sender::new(false)),
+        })
+    }
 }
 
 #[tokio::main]
@@ -82,55 +156,88 @@ async fn main() -> Result<()> {
     let listen = config.listen.as_str();
 
     // State
-    let state = AppState {
-        pending: Arc::new(Mutex::new(DashMap::new())),
-        authenticated: Arc::new(Mutex::new(DashMap::new())),
-        broadcasts: Arc::new(Mutex::new(DashMap::new())),
-        advanced_users: Arc::new(Mutex::new(config.advanced_users)),
-    };
-    
-    // Automatic update of advanced_users while the server is running
-    let advanced_users = state.advanced_users.clone();
+    let state = AppState::new(config.clone())?;
+
+    // Removing expired pending IDs, sessions and unused ping channels, syncing revocations
+    let sweeper_state = state.clone();
     tokio::spawn(async move {
         loop {
             tokio::time::sleep(std::time::Duration::from_secs(10)).await;
+            api_auth::sweep(&sweeper_state).await;
+            sweeper_state.broadcasts.sweep();
+        }
+    });
 
-            let new_config = config::Config::parse("Config.toml".into()).advanced_users;
-            let mut config = advanced_users.lock().await;
+    tokio::spawn(announcements::run(state.clone()));
 
-            if new_config != *config {
-                *config = new_config;
+    // Automatic update of advanced_users, banned_users, whitelist and announcements while the server is running
+    let reload_state = state.clone();
+    tokio::spawn(async move {
+        loop {
+            tokio::time::sleep(std::time::Duration::from_secs(10)).await;
+
+            match config::Config::load("Config.toml".into()) {
+                Ok(new_config) => reload(&reload_state, new_config).await,
+                Err(e) => warn!("Can't reload Config.toml, keeping the current one: {e}"),
             }
         }
     });
 
+    let shutdown_state = state.clone();
+    let sessions = state.authenticated.clone();
+    let app = app(state)
+        .layer(TraceLayer::new_for_http().on_request(()));
+
+    let listener = tokio::net::TcpListener::bind(listen).await?;
+    info!("Listening on {}", listener.local_addr()?);
+    axum::serve(listener, app.into_make_service_with_connect_info::<SocketAddr>())
+        .with_graceful_shutdown(async move {
+            shutdown_signal().await;
+            ws::shutdown(&shutdown_state).await;
+        })
+        .await?;
+    sessions.flush().await;
+    info!("Serve stopped. Closing...");
+    Ok(())
+}
+
+fn app(state: AppState) -> Router {
+    let motd = state.config.motd.clone();
     let api = Router::new()
         .nest(
             "//auth",
             api_auth::router()
         )
+        .route(
+            "/auth/session",
+            get(api_auth::whoami).delete(api_auth::logout)
+        )
+        .nest(
+            "/admin",
+            admin::router()
+        )
         .route(
             "/limits",
             get(api_info::limits)
-        ) // TODO:
+        )
         .route(
             "/version",
             get(api_info::version),
         )
         .route(
             "/motd",
-            get(|| async { config.motd }),
+            get(|| async move { motd }),
         )
         .route(
             "/equip",
             post(api_profile::equip_avatar)
         )
         .route(
-            "/:uuid",
+            "/{uuid}",
             get(api_profile::user_info),
         )
         .route(
-            "/:uuid/avatar",
+            "/{uuid}/avatar",
             get(api_profile::download_avatar),
         )
         .route(
@@ -142,21 +249,43 @@ async fn main() -> Result<()> {
             delete(api_profile::delete_avatar),
         ); // delete Avatar
 
-    let app = Router::new()
+    Router::new()
         .nest("/api", api)
         .route("/api/", get(api_auth::status))
         .route("/ws", get(handler))
         .route_layer(from_extractor::<api_auth::Token>())
         .with_state(state)
-        .layer(TraceLayer::new_for_http().on_request(()));
+}
 
-    let listener = tokio::net::TcpListener::bind(listen).await?;
-    info!("Listening on {}", listener.local_addr()?);
-    axum::serve(listener, app)
-        .with_graceful_shutdown(shutdown_signal())
-        .await?;
-    info!("Serve stopped. Closing...");
-    Ok(())
+/// Applies the parts of the config which may change while the server is running
+async fn reload(state: &AppState, new_config: config::Config) {
+    let mut config = state.advanced_users.lock().await;
+
+    if new_config.advanced_users != *config {
+        *config = new_config.advanced_users;
+    }
+    drop(config);
+
+    let mut whitelist = state.whitelist.lock().await;
+    if new_config.whitelist != *whitelist {
+        info!("Whitelist updated");
+        *whitelist = new_config.whitelist;
+    }
+    drop(whitelist);
+
+    let mut announcements = state.announcements.lock().await;
+    if new_config.announcements != *announcements {
+        info!("Announcements updated");
+        *announcements = new_config.announcements;
+    }
+    drop(announcements);
+
+    let mut banned_users = state.banned_users.lock().await;
+    if new_config.banned_users != *banned_users {
+        *banned_users = new_config.banned_users;
+        drop(banned_users);
+        ws::kick_banned(state).await;
+    }
 }
 
 async fn shutdown_signal() {
@@ -180,3 +309,205 @@ async fn shutdown_signal() {
     }
     info!("Terminate signal received");
 }
+
+#[cfg(all(test, feature = "dev-auth"))]
+mod tests {
+    use futures_util::{SinkExt, StreamExt};
+    use tokio::net::TcpStream;
+    use tokio_tungstenite::{tungstenite::Message, MaybeTlsStream, WebSocketStream};
+
+    use super::*;
+
+    type Socket = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+    async fn connect(addr: SocketAddr, token: &str) -> Socket {
+        let (mut ws, _) = tokio_tungstenite::connect_async(format!("ws://{addr}/ws")).await.unwrap();
+        ws.send(Message::Binary([&[0], token.as_bytes()].concat().into())).await.unwrap();
+        ws
+    }
+
+    /// Starts the server with the config, Offline provider logs in anyone
+    async fn serve(config: &str) -> (SocketAddr, AppState) {
+        let config: config::Config = toml::from_str(config).unwrap();
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let state = AppState::new(config).unwrap();
+        let app = app(state.clone());
+        tokio::spawn(async move {
+            axum::serve(listener, app.into_make_service_with_connect_info::<SocketAddr>()).await.unwrap();
+        });
+        (addr, state)
+    }
+
+    const OFFLINE: &str = r#"
+        listen = "127.0.0.1:0"
+        motd = ""
+        advancedUsers = {}
+        [admin]
+        token = "secret"
+        [limits]
+        authTimeout = 1
+        [[auth.providers]]
+        name = "Offline"
+        type = "offline"
+    "#;
+
+    /// Offline UUID of Notch
+    const NOTCH: Uuid = uuid::uuid!("b50ad385-829d-3141-a216-7e7d7539ba7f");
+
+    async fn try_login(client: &reqwest::Client, addr: SocketAddr, username: &str) -> reqwest::Response {
+        let server_id = client.get(format!("http://{addr}/api//auth/id?username={username}")).send().await.unwrap()
+            .text().await.unwrap();
+        client.get(format!("http://{addr}/api//auth/verify?id={server_id}")).send().await.unwrap()
+    }
+
+    async fn login(client: &reqwest::Client, addr: SocketAddr, username: &str) -> String {
+        try_login(client, addr, username).await
+            .error_for_status().unwrap()
+            .text().await.unwrap()
+    }
+
+    /// Connects and waits for the server to accept the token
+    async fn authenticated(addr: SocketAddr, token: &str) -> Socket {
+        let mut ws = connect(addr, token).await;
+        assert_eq!(ws.next().await.unwrap().unwrap(), Message::Binary(ws::S2CMessage::Auth.to_bytes()));
+        ws
+    }
+
+    async fn close_code(ws: &mut Socket) -> u16 {
+        match ws.next().await.unwrap().unwrap() {
+            Message::Close(Some(frame)) => frame.code.into(),
+            msg => panic!("Expected close, got {msg:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn login_and_whoami() {
+        let (addr, _) = serve(OFFLINE).await;
+        let client = reqwest::Client::new();
+        let token = login(&client, addr, "Notch").await;
+        let whoami = client.get(format!("http://{addr}/api/auth/session")).header("token", &token).send().await.unwrap()
+            .text().await.unwrap();
+        let whoami: serde_json::Value = serde_json::from_str(&whoami).unwrap();
+        assert_eq!(whoami["uuid"], NOTCH.to_string());
+        authenticated(addr, &token).await;
+    }
+
+    #[tokio::test]
+    async fn oldest_connection_is_closed_over_max_connections() {
+        let (addr, _) = serve(OFFLINE).await;
+        let token = login(&reqwest::Client::new(), addr, "Notch").await;
+        let mut first = authenticated(addr, &token).await;
+        let _second = authenticated(addr, &token).await;
+        let _third = authenticated(addr, &token).await;
+        assert_eq!(close_code(&mut first).await, 4000);
+    }
+
+    #[tokio::test]
+    async fn admin_push_reaches_every_connection() {
+        let (addr, _) = serve(OFFLINE).await;
+        let client = reqwest::Client::new();
+        let token = login(&client, addr, "Notch").await;
+        let mut connections = [authenticated(addr, &token).await, authenticated(addr, &token).await];
+        let delivered = client.post(format!("http://{addr}/api/admin/push"))
+            .bearer_auth("secret")
+            .header("content-type", "application/json")
+            .body(format!(r#"{{"target": {{"uuid": "{NOTCH}"}}, "message": {{"type": "chat", "text": "Hi"}}}}"#))
+            .send().await.unwrap()
+            .text().await.unwrap();
+        assert_eq!(delivered, r#"{"delivered":2}"#);
+        for ws in &mut connections {
+            assert_eq!(ws.next().await.unwrap().unwrap(), Message::Binary(ws::S2CMessage::Chat("Hi").to_bytes()));
+        }
+    }
+
+    #[tokio::test]
+    async fn pings_reach_subscribers() {
+        let (addr, _) = serve(OFFLINE).await;
+        let client = reqwest::Client::new();
+        let mut notch = authenticated(addr, &login(&client, addr, "Notch").await).await;
+        let mut jeb = authenticated(addr, &login(&client, addr, "jeb_").await).await;
+        jeb.send(Message::Binary(ws::C2SMessage::Sub(NOTCH).into())).await.unwrap();
+        // Sub isn't answered, so wait for it to be handled
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+        // Pings over pingSize bytes per second are dropped, the rest come with the sender's UUID
+        notch.send(Message::Binary(ws::C2SMessage::Ping(6, true, &[0; 2048]).into())).await.unwrap();
+        notch.send(Message::Binary(ws::C2SMessage::Ping(7, true, b"hi").into())).await.unwrap();
+        assert_eq!(jeb.next().await.unwrap().unwrap(), Message::Binary(ws::S2CMessage::Ping(NOTCH, 7, true, b"hi").to_bytes()));
+    }
+
+    #[tokio::test]
+    async fn unauthenticated_connections_are_closed() {
+        let (addr, _) = serve(OFFLINE).await;
+        // Unknown tokens are rejected with 3000
+        assert_eq!(close_code(&mut connect(addr, "nope").await).await, 3000);
+
+        // So is anything but Token before authentication, or nothing at all
+        let (mut ws, _) = tokio_tungstenite::connect_async(format!("ws://{addr}/ws")).await.unwrap();
+        ws.send(Message::Binary([2; 17].to_vec().into())).await.unwrap();
+        assert_eq!(close_code(&mut ws).await, 3000);
+        let (mut ws, _) = tokio_tungstenite::connect_async(format!("ws://{addr}/ws")).await.unwrap();
+        assert_eq!(close_code(&mut ws).await, 3000);
+    }
+
+    #[tokio::test]
+    async fn bans() {
+        let (addr, state) = serve(OFFLINE).await;
+        let client = reqwest::Client::new();
+        let ban = |expires: chrono::TimeDelta| config::Ban { reason: String::from("Griefing"), expires: Some(Utc::now() + expires) };
+
+        // An expired ban doesn't stop anybody
+        state.banned_users.lock().await.insert(NOTCH, ban(chrono::TimeDelta::hours(-1)));
+        let token = login(&client, addr, "Notch").await;
+        let mut ws = authenticated(addr, &token).await;
+
+        // A banned user can't upload avatars
+        state.banned_users.lock().await.insert(NOTCH, ban(chrono::TimeDelta::hours(1)));
+        let upload = client.put(format!("http://{addr}/api/avatar")).header("token", &token).body(vec![0; 16]).send().await.unwrap();
+        assert_eq!(upload.status(), reqwest::StatusCode::FORBIDDEN);
+
+        // Connected users are closed with 4001 when the ban comes with a reloaded config
+        let mut new_config: config::Config = toml::from_str(OFFLINE).unwrap();
+        new_config.banned_users.insert(NOTCH, ban(chrono::TimeDelta::hours(1)));
+        state.banned_users.lock().await.clear();
+        reload(&state, new_config).await;
+        assert_eq!(close_code(&mut ws).await, 4001);
+
+        // And can't log in again
+        assert_eq!(try_login(&client, addr, "Notch").await.status(), reqwest::StatusCode::FORBIDDEN);
+    }
+
+    #[tokio::test]
+    async fn namespaced_accounts_have_own_connections() {
+        let (addr, state) = serve(r#"
+            listen = "127.0.0.1:0"
+            motd = ""
+            advancedUsers = {}
+            [limits]
+            maxConnections = 1
+            [auth]
+            collisionPolicy = { namespace = "Offline" }
+            [[auth.providers]]
+            name = "Offline"
+            type = "offline"
+            [[auth.providers]]
+            name = "Other"
+            type = "offline"
+        "#).await;
+
+        // The same UUID from two providers
+        let uuid = Uuid::from_u128(1);
+        for (token, auth_system) in [("primary", "Offline"), ("other", "Other")] {
+            let userinfo = Userinfo { username: String::from("Notch"), uuid, auth_system: auth_system.to_string(), created: Utc::now(), ip: None };
+            state.authenticated.insert(token.to_string(), userinfo).await;
+        }
+        let _primary = authenticated(addr, "primary").await;
+        let mut other = authenticated(addr, "other").await;
+        assert_eq!(ws::connections(&state, Some(&[uuid])).await.len(), 2);
+
+        // Each account is still limited on its own
+        let _again = authenticated(addr, "other").await;
+        assert_eq!(close_code(&mut other).await, 4000);
+        assert_eq!(ws::connections(&state, Some(&[uuid])).await.len(), 2);
+    }
+}
@@ -1,22 +1,52 @@
-use anyhow_http::{http_error_ret, response::Result};
-use axum::{body::Bytes, debug_handler, extract::{Path, State}, Json};
+use axum::{body::Bytes, debug_handler, extract::{Path, State}, http::StatusCode, response::{IntoResponse, Response}, Json};
 use log::{debug, warn};
 use serde_json::{json, Value};
 use tokio::{fs, io::{AsyncReadExt, BufWriter, self}};
 use uuid::Uuid;
 
-use crate::{auth::Token, utils::{calculate_file_sha256, format_uuid, get_correct_array}, ws::S2CMessage, AppState};
+use crate::{auth::{authenticate, banned, requester, storage_uuid, ClientIp, Token}, utils::{calculate_file_sha256, format_uuid, get_correct_array}, ws::S2CMessage, AppState};
+
+/// Error answered as {"error": reason}
+#[derive(Debug)]
+pub struct HttpError(StatusCode, String);
+
+impl IntoResponse for HttpError {
+    fn into_response(self) -> Response {
+        (self.0, Json(json!({ "error": self.1 }))).into_response()
+    }
+}
+
+impl From<io::Error> for HttpError {
+    fn from(e: io::Error) -> Self {
+        warn!("[API] {e}");
+        Self(StatusCode::INTERNAL_SERVER_ERROR, String::from("Internal Server Error"))
+    }
+}
+
+type Result<T> = std::result::Result<T, HttpError>;
+
+macro_rules! http_error_ret {
+    ($code:ident, $reason:expr) => {
+        return Err(HttpError(StatusCode::$code, String::from($reason)))
+    };
+}
 
 #[debug_handler]
 pub async fn user_info(
     Path(uuid): Path<Uuid>,
-    State(state): State<AppState>, // FIXME: Variable doesn't using!
+    Token(token): Token,
+    ClientIp(ip): ClientIp,
+    State(state): State<AppState>,
 ) -> Json<Value> {
     log::info!("Receiving profile information for {}",uuid);
 
     let formatted_uuid = format_uuid(uuid);
 
-    let avatar_file = format!("avatars/{}.moon", formatted_uuid);
+    // With "namespace" collision policy the requester sees players from their own provider,
+    // along with their avatars, badges and bans
+    let requester = requester(&state, token, ip).await;
+    let storage_uuid = storage_uuid(&state, uuid, requester.as_ref().map(|u| u.auth_system.as_str()));
+    let avatar_file = format!("avatars/{}.moon", format_uuid(storage_uuid));
 
     let mut user_info_response = json!({
         "uuid": &formatted_uuid,
@@ -28,10 +58,10 @@ pub async fn user_info(
             "pride": [0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0]
         },
         "version": "0.1.4+1.20.1",
-        "banned": false
+        "banned": banned(&state, storage_uuid).await.is_some()
     });
 
-    if let Some(settings) = state.advanced_users.lock().await.get(&formatted_uuid) {
+    if let Some(settings) = state.advanced_users.lock().await.get(&format_uuid(storage_uuid)) {
         let pride = get_correct_array(settings.get("pride").unwrap());
         let special = get_correct_array(settings.get("special").unwrap());
         let badges = user_info_response.get_mut("equippedBadges").and_then(Value::as_object_mut).unwrap();
@@ -63,9 +93,13 @@ pub async fn user_info(
 #[debug_handler]
 pub async fn download_avatar(
     Path(uuid): Path<Uuid>,
+    Token(token): Token,
+    ClientIp(ip): ClientIp,
+    State(state): State<AppState>,
 ) -> Result<Vec<u8>> {
-    let uuid = format_uuid(uuid);
     log::info!("Requesting an avatar: {}", uuid);
+    let requester = requester(&state, token, ip).await;
+    let uuid = format_uuid(storage_uuid(&state, uuid, requester.as_ref().map(|u| u.auth_system.as_str())));
     let mut file = if let Ok(file) = fs::File::open(format!("avatars/{}.moon", uuid)).await {
         file
     } else {
@@ -84,6 +118,7 @@ pub async fn download_avatar(
 #[debug_handler]
 pub async fn upload_avatar(
     Token(token): Token,
+    ClientIp(ip): ClientIp,
     State(state): State<AppState>,
     body: Bytes,
 ) -> Result<String>  {
@@ -94,43 +129,59 @@ pub async fn upload_avatar(
         Some(t) => t,
         None => http_error_ret!(UNAUTHORIZED, "Authentication error!"),
     };
-    let userinfos = state.authenticated.lock().await;
-
-    if let Some(user_info) = userinfos.get(token.as_str()) {
-        log::info!("{} ({}) trying to upload an avatar",user_info.uuid,user_info.username);
-        let avatar_file = format!("avatars/{}.moon",user_info.uuid);
-        let mut file = BufWriter::new(fs::File::create(&avatar_file).await?);
-        io::copy(&mut request_data.as_ref(), &mut file).await?;
+    let user_info = match authenticate(&state, &token, ip).await {
+        Some(u) => u,
+        None => http_error_ret!(UNAUTHORIZED, "Authentication error!"),
+    };
+    if banned(&state, user_info.storage_uuid(&state)).await.is_some() {
+        http_error_ret!(FORBIDDEN, "You are banned!");
     }
-    Ok(format!("ok"))
+    log::info!("{} ({}) trying to upload an avatar",user_info.uuid,user_info.username);
+    let avatar_file = format!("avatars/{}.moon",user_info.storage_uuid(&state));
+    let mut file = BufWriter::new(fs::File::create(&avatar_file).await?);
+    io::copy(&mut request_data.as_ref(), &mut file).await?;
+    Ok(String::from("ok"))
 }
 
 pub async fn equip_avatar(
     Token(token): Token,
+    ClientIp(ip): ClientIp,
     State(state): State<AppState>,
-) -> String {
+) -> Result<String> {
     debug!("[API] S2C : Equip");
-    let uuid = state.authenticated.lock().await.get(&token.unwrap()).unwrap().uuid;
-    if state.broadcasts.lock().await.get(&uuid).unwrap().send(S2CMessage::Event(uuid).to_vec()).is_err() {
+    let user_info = match requester(&state, token, ip).await {
+        Some(u) => u,
+        None => http_error_ret!(UNAUTHORIZED, "Authentication error!"),
+    };
+    let uuid = user_info.uuid;
+    if banned(&state, user_info.storage_uuid(&state)).await.is_some() {
+        http_error_ret!(FORBIDDEN, "You are banned!");
+    }
+    if state.broadcasts.send(user_info.storage_uuid(&state), S2CMessage::Event(uuid).to_bytes()) == 0 {
         warn!("[WebSocket] Failed to send Event! Maybe there is no one to send")  // FIXME: Засунуть в Handler
     };
-    format!("ok")
+    Ok(String::from("ok"))
 }
 
 pub async fn delete_avatar(
     Token(token): Token,
+    ClientIp(ip): ClientIp,
     State(state): State<AppState>,
 ) -> Result<String> {
     let token = match token {
         Some(t) => t,
         None => http_error_ret!(UNAUTHORIZED, "Authentication error!"),
     };
-    let userinfos = state.authenticated.lock().await;
-    if let Some(user_info) = userinfos.get(token.as_str()) {
-        log::info!("{} ({}) is trying to delete the avatar",user_info.uuid,user_info.username);
-        let avatar_file = format!("avatars/{}.moon",user_info.uuid);
-        fs::remove_file(avatar_file).await?;
+    let user_info = match authenticate(&state, &token, ip).await {
+        Some(u) => u,
+        None => http_error_ret!(UNAUTHORIZED, "Authentication error!"),
+    };
+    if banned(&state, user_info.storage_uuid(&state)).await.is_some() {
+        http_error_ret!(FORBIDDEN, "You are banned!");
     }
+    log::info!("{} ({}) is trying to delete the avatar",user_info.uuid,user_info.username);
+    let avatar_file = format!("avatars/{}.moon",user_info.storage_uuid(&state));
+    fs::remove_file(avatar_file).await?;
     // let avatar_file = format!("avatars/{}.moon",user_info.uuid);
-    Ok(format!("ok"))
+    Ok(String::from("ok"))
 }
\ No newline at end of file
@@ -0,0 +1,94 @@
+use std::{hash::Hash, time::{Duration, Instant}};
+
+use dashmap::DashMap;
+
+/// Token bucket: holds up to `burst` tokens and gets `rate` tokens per second back
+#[derive(Debug, Clone)]
+pub struct Bucket {
+    tokens: f64,
+    last: Instant,
+}
+
+impl Bucket {
+    pub fn new(burst: f64) -> Self {
+        Self { tokens: burst, last: Instant::now() }
+    }
+
+    fn refill(&mut self, rate: f64, burst: f64) {
+        let now = Instant::now();
+        self.tokens = (self.tokens + now.duration_since(self.last).as_secs_f64() * rate).min(burst);
+        self.last = now;
+    }
+
+    /// Takes a token or returns how long to wait for the next one
+    pub fn take(&mut self, rate: f64, burst: f64) -> Result<(), Duration> {
+        self.take_n(1.0, rate, burst)
+    }
+
+    /// Takes `n` tokens at once or returns how long to wait for them, e.g. bytes of a message
+    pub fn take_n(&mut self, n: f64, rate: f64, burst: f64) -> Result<(), Duration> {
+        self.refill(rate, burst);
+        if self.tokens >= n {
+            self.tokens -= n;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64((n - self.tokens) / rate))
+        }
+    }
+}
+
+/// Separate token bucket for every key, e.g. client IP
+#[derive(Debug)]
+pub struct RateLimiter<K: Hash + Eq> {
+    rate: f64,
+    burst: f64,
+    buckets: DashMap<K, Bucket>,
+}
+
+impl<K: Hash + Eq> RateLimiter<K> {
+    /// Allows `per_minute` requests at once and then `per_minute` requests per minute. 0 disables the limit
+    pub fn per_minute(per_minute: u32) -> Self {
+        Self { rate: per_minute as f64 / 60.0, burst: per_minute as f64, buckets: DashMap::new() }
+    }
+
+    /// Returns how long to wait if the key is over the limit
+    pub fn check(&self, key: K) -> Result<(), Duration> {
+        if self.rate == 0.0 {
+            return Ok(());
+        }
+        self.buckets.entry(key).or_insert_with(|| Bucket::new(self.burst)).take(self.rate, self.burst)
+    }
+
+    /// Forgets keys whose buckets are full again, they are the same as new ones
+    pub fn sweep(&self) {
+        let (rate, burst) = (self.rate, self.burst);
+        self.buckets.retain(|_, bucket| {
+            bucket.refill(rate, burst);
+            bucket.tokens < burst
+        });
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn limits_per_key() {
+    let limiter = RateLimiter::per_minute(2);
+    assert!(limiter.check(1).is_ok());
+    assert!(limiter.check(1).is_ok());
+    let wait = limiter.check(1).unwrap_err();
+    assert!(wait > Duration::from_secs(29) && wait <= Duration::from_secs(30));
+    assert!(limiter.check(2).is_ok());
+
+    assert!(RateLimiter::per_minute(0).check(1).is_ok());
+}
+
+#[cfg(test)]
+#[test]
+fn takes_many_tokens() {
+    let mut bucket = Bucket::new(1024.0);
+    assert!(bucket.take_n(1000.0, 1024.0, 1024.0).is_ok());
+    assert!(bucket.take_n(100.0, 1024.0, 1024.0).is_err());
+    assert!(bucket.take_n(20.0, 1024.0, 1024.0).is_ok());
+    // More than the burst never fits
+    assert!(Bucket::new(1024.0).take_n(2048.0, 1024.0, 1024.0).is_err());
+}
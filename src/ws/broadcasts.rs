@@ -0,0 +1,197 @@
+use std::{collections::VecDeque, sync::{Arc, Mutex}};
+
+use bytes::Bytes;
+use dashmap::{mapref::one::RefMut, DashMap};
+use log::debug;
+use tokio::sync::Notify;
+use uuid::Uuid;
+
+const CAPACITY: usize = 64; // Per subscription, an outbox is shared by all subscriptions of its connection
+
+/// Routes pings from publishers to the outboxes of their subscribers, keyed by storage UUID.
+/// An entry lives while its user has connections (publishers) or somebody is subscribed to it,
+/// so subscribers keep getting pings after the user reconnects.
+#[derive(Debug, Default)]
+pub struct Broadcasts(DashMap<Uuid, Channel>);
+
+#[derive(Debug, Default)]
+struct Channel {
+    publishers: usize,
+    subscribers: Vec<Arc<Outbox>>,
+}
+
+impl Channel {
+    fn is_unused(&self) -> bool {
+        self.publishers == 0 && self.subscribers.is_empty()
+    }
+}
+
+impl Broadcasts {
+    fn channel(&self, uuid: Uuid) -> RefMut<'_, Uuid, Channel> {
+        self.0.entry(uuid).or_default()
+    }
+
+    /// Registers a connection of the user, call `unpublish` when it's closed
+    pub fn publish(&self, uuid: Uuid) {
+        self.channel(uuid).publishers += 1;
+    }
+
+    pub fn unpublish(&self, uuid: Uuid) {
+        if let Some(mut channel) = self.0.get_mut(&uuid) {
+            channel.publishers = channel.publishers.saturating_sub(1);
+        }
+        self.0.remove_if(&uuid, |_, channel| channel.is_unused());
+    }
+
+    /// Works even if the user isn't connected yet
+    pub fn subscribe(&self, uuid: Uuid, outbox: &Arc<Outbox>) {
+        let mut channel = self.channel(uuid);
+        if !channel.subscribers.iter().any(|s| Arc::ptr_eq(s, outbox)) {
+            channel.subscribers.push(outbox.clone());
+            outbox.inner.lock().unwrap().subscriptions += 1;
+        }
+    }
+
+    pub fn unsubscribe(&self, uuid: Uuid, outbox: &Arc<Outbox>) {
+        if let Some(mut channel) = self.0.get_mut(&uuid) {
+            let before = channel.subscribers.len();
+            channel.subscribers.retain(|s| !Arc::ptr_eq(s, outbox));
+            if channel.subscribers.len() != before {
+                let mut inner = outbox.inner.lock().unwrap();
+                inner.subscriptions = inner.subscriptions.saturating_sub(1);
+            }
+        }
+        self.0.remove_if(&uuid, |_, channel| channel.is_unused());
+    }
+
+    /// Puts the message into every subscriber's outbox, they all share the same buffer. Returns the number of subscribers
+    pub fn send(&self, uuid: Uuid, msg: Bytes) -> usize {
+        match self.0.get(&uuid) {
+            Some(channel) => {
+                for outbox in &channel.subscribers {
+                    outbox.push(msg.clone());
+                }
+                channel.subscribers.len()
+            },
+            None => 0,
+        }
+    }
+
+    /// Removes outboxes of connections which are gone and channels nobody uses
+    pub fn sweep(&self) {
+        let before = self.0.len();
+        self.0.retain(|_, channel| {
+            // Only we hold the outbox, so the connection has been dropped without unsubscribing
+            channel.subscribers.retain(|s| Arc::strong_count(s) > 1);
+            !channel.is_unused()
+        });
+        let removed = before - self.0.len();
+        if removed != 0 {
+            debug!("[Broadcasts] Swept {removed} unused channels");
+        }
+    }
+}
+
+/// Pings waiting to be sent to one connection. It holds `CAPACITY` pings for every subscription,
+/// so a burst from all of them fits. If the connection can't keep up, the oldest ones are dropped.
+#[derive(Debug, Default)]
+pub struct Outbox {
+    inner: Mutex<Inner>,
+    notify: Notify,
+}
+
+#[derive(Debug, Default)]
+struct Inner {
+    queue: VecDeque<Bytes>,
+    subscriptions: usize,
+    lagging: bool, // Dropped something since the queue was empty last time
+    lags: u64, // Times the connection fell behind, not taken yet
+    skipped: u64, // Dropped pings in total
+}
+
+impl Outbox {
+    pub fn push(&self, msg: Bytes) {
+        let mut inner = self.inner.lock().unwrap();
+        if inner.queue.len() >= CAPACITY * inner.subscriptions.max(1) {
+            inner.queue.pop_front();
+            inner.skipped += 1;
+            if !inner.lagging {
+                inner.lagging = true;
+                inner.lags += 1;
+            }
+        }
+        inner.queue.push_back(msg);
+        drop(inner);
+        self.notify.notify_one();
+    }
+
+    /// Cancel safe, the message is taken only when the future completes
+    pub async fn recv(&self) -> Bytes {
+        loop {
+            {
+                let mut inner = self.inner.lock().unwrap();
+                if let Some(msg) = inner.queue.pop_front() {
+                    if inner.queue.is_empty() {
+                        inner.lagging = false;
+                    }
+                    return msg;
+                }
+            }
+            self.notify.notified().await;
+        }
+    }
+
+    /// Returns how many times the connection fell behind since the last call and how many pings it missed in total
+    pub fn take_lags(&self) -> (u64, u64) {
+        let mut inner = self.inner.lock().unwrap();
+        (std::mem::take(&mut inner.lags), inner.skipped)
+    }
+}
+
+#[cfg(test)]
+#[tokio::test]
+async fn subscribers_survive_reconnect() {
+    let broadcasts = Broadcasts::default();
+    let uuid = Uuid::from_u128(1);
+
+    // Subscribed before the user connected
+    let outbox = Arc::new(Outbox::default());
+    broadcasts.subscribe(uuid, &outbox);
+    broadcasts.publish(uuid);
+    assert_eq!(broadcasts.send(uuid, Bytes::from(vec![1])), 1);
+    assert_eq!(outbox.recv().await, vec![1]);
+
+    // Reconnected
+    broadcasts.unpublish(uuid);
+    broadcasts.publish(uuid);
+    assert_eq!(broadcasts.send(uuid, Bytes::from(vec![2])), 1);
+    assert_eq!(outbox.recv().await, vec![2]);
+
+    // Slow subscriber misses the oldest pings
+    for i in 0..CAPACITY as u8 + 2 {
+        broadcasts.send(uuid, Bytes::from(vec![i]));
+    }
+    assert_eq!(outbox.take_lags(), (1, 2));
+    assert_eq!(outbox.recv().await, vec![2]);
+    for _ in 0..CAPACITY - 1 {
+        outbox.recv().await;
+    }
+
+    // Capacity grows with subscriptions, so a burst from all of them isn't counted as lag
+    let other = Uuid::from_u128(2);
+    broadcasts.subscribe(other, &outbox);
+    for i in 0..CAPACITY as u8 {
+        broadcasts.send(uuid, Bytes::from(vec![i]));
+        broadcasts.send(other, Bytes::from(vec![i]));
+    }
+    assert_eq!(outbox.take_lags(), (0, 2));
+    broadcasts.unsubscribe(other, &outbox);
+
+    // Channel is kept while anybody uses it
+    broadcasts.unpublish(uuid);
+    broadcasts.sweep();
+    assert_eq!(broadcasts.0.len(), 1);
+    drop(outbox);
+    broadcasts.sweep();
+    assert_eq!(broadcasts.0.len(), 0);
+}
@@ -1,8 +1,10 @@
 use uuid::Uuid;
 
 use super::MessageLoadError;
+use bytes::{BufMut, Bytes, BytesMut};
 use std::convert::{TryFrom, TryInto};
 
+/// Parsed in place, payloads borrow from the received frame
 #[repr(u8)]
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum C2SMessage<'a> {
@@ -15,7 +17,7 @@ pub enum C2SMessage<'a> {
 impl<'a> TryFrom<&'a [u8]> for C2SMessage<'a> {
     type Error = MessageLoadError;
     fn try_from(buf: &'a [u8]) -> Result<Self, <Self as TryFrom<&'a [u8]>>::Error> {
-        if buf.len() == 0 {
+        if buf.is_empty() {
             Err(MessageLoadError::BadLength("C2SMessage", 1, false, 0))
         } else {
             match buf[0] {
@@ -73,19 +75,59 @@ impl<'a> TryFrom<&'a [u8]> for C2SMessage<'a> {
         }
     }
 }
-impl<'a> Into<Box<[u8]>> for C2SMessage<'a> {
-    fn into(self) -> Box<[u8]> {
-        use std::iter;
-        let a: Box<[u8]> = match self {
-            C2SMessage::Token(t) => iter::once(0).chain(t.into_iter().copied()).collect(),
-            C2SMessage::Ping(p, s, d) => iter::once(1)
-                .chain(p.to_be_bytes())
-                .chain(iter::once(s.into()))
-                .chain(d.into_iter().copied())
-                .collect(),
-            C2SMessage::Sub(s) => iter::once(2).chain(s.into_bytes()).collect(),
-            C2SMessage::Unsub(s) => iter::once(3).chain(s.into_bytes()).collect(),
-        };
-        a
+impl<'a> From<C2SMessage<'a>> for Bytes {
+    fn from(val: C2SMessage<'a>) -> Self {
+        let mut buf = BytesMut::new();
+        match val {
+            C2SMessage::Token(t) => {
+                buf.put_u8(0);
+                buf.put_slice(t);
+            }
+            C2SMessage::Ping(p, s, d) => {
+                buf.put_u8(1);
+                buf.put_u32(p);
+                buf.put_u8(s.into());
+                buf.put_slice(d);
+            }
+            C2SMessage::Sub(s) => {
+                buf.put_u8(2);
+                buf.put_slice(s.as_bytes());
+            }
+            C2SMessage::Unsub(s) => {
+                buf.put_u8(3);
+                buf.put_slice(s.as_bytes());
+            }
+        }
+        buf.freeze()
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    proptest! {
+        #[test]
+        fn round_trip(
+            kind in 0..4u8,
+            uuid in any::<u128>().prop_map(Uuid::from_u128),
+            id in any::<u32>(),
+            sync in any::<bool>(),
+            data in any::<Vec<u8>>(),
+        ) {
+            let msg = match kind {
+                0 => C2SMessage::Token(&data),
+                1 => C2SMessage::Ping(id, sync, &data),
+                2 => C2SMessage::Sub(uuid),
+                _ => C2SMessage::Unsub(uuid),
+            };
+            let buf: Bytes = msg.clone().into();
+            prop_assert_eq!(C2SMessage::try_from(buf.as_ref()).unwrap(), msg);
+        }
+
+        #[test]
+        fn decoding_never_panics(buf in any::<Vec<u8>>()) {
+            let _ = C2SMessage::try_from(buf.as_slice());
+        }
     }
-}
\ No newline at end of file
+}
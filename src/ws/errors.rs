@@ -1,10 +1,12 @@
 use std::fmt::*;
 use std::ops::RangeInclusive;
+use std::str::Utf8Error;
 
 #[derive(Debug)]
 pub enum MessageLoadError {
     BadEnum(&'static str, RangeInclusive<usize>, usize),
     BadLength(&'static str, usize, bool, usize),
+    BadUtf8(&'static str, Utf8Error),
 }
 impl Display for MessageLoadError {
     fn fmt(&self, fmt: &mut Formatter) -> Result {
@@ -20,6 +22,7 @@ impl Display for MessageLoadError {
                 "buffer wrong size for {f}: must be {} {n} bytes, got {c}",
                 if *e { "exactly" } else { "at least" }
             ),
+            Self::BadUtf8(f, e) => write!(fmt, "invalid text in {f}: {e}"),
         }
     }
 }
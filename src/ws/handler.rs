@@ -1,28 +1,76 @@
-use std::sync::Arc;
+use std::{collections::HashMap, net::IpAddr, sync::Arc, time::Duration};
 
-use axum::{extract::{ws::{Message, WebSocket}, State, WebSocketUpgrade}, response::Response};
-use dashmap::DashMap;
+use axum::{body::Bytes, extract::{ws::{CloseFrame, Message, WebSocket}, State, WebSocketUpgrade}, response::Response};
 use log::{debug, error, info, trace, warn};
-use tokio::sync::{broadcast::{self, Receiver}, mpsc, Notify};
+use tokio::sync::{mpsc::{self, error::TrySendError}, Notify};
 use uuid::Uuid;
 
-use crate::{ws::{C2SMessage, S2CMessage}, AppState};
+use crate::{announcements, auth::{banned, check_session, storage_uuid, ClientIp, SessionError}, config::ConnectionOverflow, ratelimit::Bucket, ws::{C2SMessage, Outbox, S2CMessage}, AppState};
 
 pub async fn handler(
     ws: WebSocketUpgrade,
+    ClientIp(ip): ClientIp,
     State(state): State<AppState>,
 ) -> Response {
-    ws.on_upgrade(|socket| handle_socket(socket, state))
+    ws.on_upgrade(move |socket| handle_socket(socket, state, ip))
 }
 
+/// Messages for a connection from other parts of the server
+#[derive(Debug, Clone)]
+pub enum SessionMessage {
+    Binary(Bytes),
+    Close(CloseCode, String), // (code, reason)
+}
+
+/// Close codes known by Figura, see note.txt
+#[repr(u16)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CloseCode {
+    ProtocolError = 1002,
+    UnsupportedData = 1003,
+    ServiceRestart = 1012,
+    TryAgainLater = 1013,
+    Unauthorized = 3000,
+    ReAuth = 4000,
+    Banned = 4001,
+    TooManyConnections = 4002,
+}
+
+/// Live connection of an authenticated user
+#[derive(Debug, Clone)]
+pub struct Connection {
+    pub uuid: Uuid, // user_connections is keyed by storage UUID, this is the real one
+    pub token: String,
+    pub tx: mpsc::Sender<SessionMessage>,
+    kill: Arc<Notify>, // Drops the connection without a close frame
+}
+
+impl Connection {
+    /// Queues the message without waiting for a stalled client.
+    /// If a Close doesn't fit, the connection is dropped right away. Returns false if the message is lost.
+    fn send(&self, msg: SessionMessage) -> bool {
+        match self.tx.try_send(msg) {
+            Ok(()) => true,
+            Err(TrySendError::Full(SessionMessage::Close(..))) => {
+                self.kill.notify_one();
+                true
+            },
+            Err(_) => false,
+        }
+    }
+}
+
+/// Connection state: unauthenticated until a valid Token, which is the only message allowed before it
 #[derive(Debug, Clone)]
 struct WSOwner(Option<WSUser>);
 
 #[derive(Debug, Clone)]
 struct WSUser {
     username: String,
-    token: String,
+    tx: mpsc::Sender<SessionMessage>, // Identifies the connection in user_connections
     uuid: Uuid,
+    auth_system: String,
+    storage_uuid: Uuid, // Key in broadcasts, see collisionPolicy
 }
 
 impl WSOwner {
@@ -35,176 +83,367 @@ impl WSOwner {
     }
 }
 
-async fn handle_socket(mut socket: WebSocket, state: AppState) {
+async fn handle_socket(mut socket: WebSocket, state: AppState, ip: IpAddr) {
     debug!("[WebSocket] New unknown connection!");
     let mut owner = WSOwner(None);
-    let cutoff: DashMap<Uuid, Arc<Notify>> = DashMap::new();
+    let outbox = Arc::new(Outbox::default());
+    let mut subscriptions = Subscriptions { state: state.clone(), outbox: outbox.clone(), keys: HashMap::new() };
     let (mtx, mut mrx) = mpsc::channel(64);
-    let mut bctx: Option<broadcast::Sender<Vec<u8>>> = None;
+    let kill = Arc::new(Notify::new());
+    // Every lag takes a token, so only a connection which lags again and again runs out of them
+    let max_lag = state.config.limits.max_lag as f64;
+    let mut lag_bucket = Bucket::new(max_lag);
+    let ping_rate = state.config.limits.ping_rate as f64;
+    let mut ping_bucket = Bucket::new(ping_rate);
+    let ping_size = state.config.limits.ping_size as f64;
+    let mut ping_size_bucket = Bucket::new(ping_size);
+    let mut shutdown = state.shutdown.subscribe();
+    let auth_timeout = tokio::time::sleep(Duration::from_secs(state.config.limits.auth_timeout));
+    tokio::pin!(auth_timeout);
     loop {
         tokio::select! {
             Some(msg) = socket.recv() => {
                 trace!("[WebSocket{}] Raw: {msg:?}", owner.name());
                 let mut msg = if let Ok(msg) = msg {
-                    if let Message::Close(_) = msg {
-                        info!("[WebSocket{}] Connection successfully closed!", owner.name());
-                        if let Some(u) = owner.0 {
-                            remove_broadcast(state.broadcasts.clone(), u.uuid).await;
-                        }
-                        return;
+                    match msg {
+                        Message::Close(_) => {
+                            info!("[WebSocket{}] Connection successfully closed!", owner.name());
+                            if let Some(u) = owner.0 {
+                                remove_user(&state, &u).await;
+                            }
+                            return;
+                        },
+                        // Answered by axum
+                        Message::Ping(_) | Message::Pong(_) => continue,
+                        Message::Text(_) => {
+                            warn!("[WebSocket{}] Text frames aren't supported! Connection terminated!", owner.name());
+                            close(&mut socket, &state, owner, CloseCode::UnsupportedData, "Only binary frames are supported").await;
+                            return;
+                        },
+                        Message::Binary(_) => msg,
                     }
-                    msg
                 } else {
                     warn!("[WebSocket{}] Receive error! Connection terminated!", owner.name());
                     if let Some(u) = owner.0 {
-                        remove_broadcast(state.broadcasts.clone(), u.uuid).await;
+                        remove_user(&state, &u).await;
                     }
                     return;
                 };
                 // Далее код для обработки msg
-                let msg_vec = msg.clone().into_data();
-                let msg_array = msg_vec.as_slice();
+                let frame = msg.into_data();
                 
-                let newmsg = match C2SMessage::try_from(msg_array) {
+                let newmsg = match C2SMessage::try_from(frame.as_ref()) {
                     Ok(data) => data,
                     Err(e) => {
                         error!("[WebSocket{}] This message is not from Figura! {e:?}", owner.name());
-                        if let Some(u) = owner.0 {
-                            remove_broadcast(state.broadcasts.clone(), u.uuid).await;
-                        }
+                        close(&mut socket, &state, owner, CloseCode::ProtocolError, &format!("Malformed message: {e:?}")).await;
                         return;
                     },
                 };
         
                 debug!("[WebSocket{}] Raw: {newmsg:?}", owner.name());
         
-                match newmsg {
-                    C2SMessage::Token(token) => { // FIXME: Написать переменную спомощью которой бужет проверяться авторизовался ли пользователь или нет
-                    debug!("[WebSocket{}] C2S : Token", owner.name());
-                        let token = String::from_utf8(token.to_vec()).unwrap();
-                        let authenticated = state.authenticated.lock().await;
-                        match authenticated.get(&token) { // Принцип прост: если токена в authenticated нет, значит это trash
-                            Some(t) => {
+                match (owner.0.clone(), newmsg) {
+                    (None, C2SMessage::Token(token)) => {
+                        debug!("[WebSocket{}] C2S : Token", owner.name());
+                        let token = match String::from_utf8(token.to_vec()) {
+                            Ok(token) => token,
+                            Err(_) => {
+                                warn!("[WebSocket{}] Token isn't UTF-8! Connection terminated!", owner.name());
+                                close(&mut socket, &state, owner, CloseCode::ProtocolError, "Malformed token").await;
+                                return;
+                            },
+                        };
+                        match check_session(&state, &token, ip).await { // Принцип прост: если токена в authenticated нет (или он истёк), значит это trash
+                            Ok(t) => {
+                                let storage_uuid = t.storage_uuid(&state);
+                                if let Some(ban) = banned(&state, storage_uuid).await {
+                                    warn!("[WebSocket] {} is banned! Connection terminated!", t.username);
+                                    close(&mut socket, &state, owner, CloseCode::Banned, &ban.reason).await;
+                                    return;
+                                }
                                 //username = t.username.clone();
-                                owner.0 = Some(WSUser { username: t.username.clone(), token, uuid: t.uuid });
-                                msg = Message::Binary(S2CMessage::Auth.to_vec());
-                                let bcs = state.broadcasts.lock().await;
-                                match bcs.get(&t.uuid) {
-                                    Some(tx) => {
-                                        bctx = Some(tx.to_owned());
-                                    },
-                                    None => {
-                                        let (tx, _rx) = broadcast::channel(64);
-                                        bcs.insert(t.uuid, tx.clone());
-                                        bctx = Some(tx.to_owned());
-                                    },
-                                };
+                                info!("[WebSocket] {} authenticated via {}", t.username, t.auth_system);
+                                let conn = Connection { uuid: t.uuid, token, tx: mtx.clone(), kill: kill.clone() };
+                                if let Err(code) = add_connection(&state, storage_uuid, conn).await {
+                                    warn!("[WebSocket] {} has too many connections! Connection terminated!", t.username);
+                                    close(&mut socket, &state, owner, code, "Too many connections").await;
+                                    return;
+                                }
+                                state.broadcasts.publish(storage_uuid);
+                                owner.0 = Some(WSUser { username: t.username.clone(), tx: mtx.clone(), uuid: t.uuid, auth_system: t.auth_system.clone(), storage_uuid });
+                                msg = Message::Binary(S2CMessage::Auth.to_bytes());
+                                // Queued, so they are sent after Auth
+                                announcements::on_auth(&state, t.uuid, &mtx).await;
                             },
-                            None => {
-                                warn!("[WebSocket] Authenticaton error! Connection terminated!");
+                            Err(e) => {
+                                warn!("[WebSocket] Authenticaton error: {e}! Connection terminated!");
                                 debug!("[WebSocket] Tried to log in with {token}"); // Tried to log in with token: {token}
-                                if let Some(u) = owner.0 {
-                                    remove_broadcast(state.broadcasts.clone(), u.uuid).await;
-                                }
-                                return; // TODO: Прописать код отключения
+                                // The client gets a new token on 4000, it's pointless for unknown ones
+                                let code = match e {
+                                    SessionError::Expired | SessionError::Revoked => CloseCode::ReAuth,
+                                    SessionError::Unknown | SessionError::WrongIp => CloseCode::Unauthorized,
+                                };
+                                close(&mut socket, &state, owner, code, &e.to_string()).await;
+                                return;
                             },
                         };
                     },
-                    C2SMessage::Ping(_, _, _) => {
+                    (Some(_), C2SMessage::Token(_)) => {
+                        warn!("[WebSocket{}] Token after authentication! Connection terminated!", owner.name());
+                        close(&mut socket, &state, owner, CloseCode::ProtocolError, "Already authenticated").await;
+                        return;
+                    },
+                    (None, msg) => {
+                        warn!("[WebSocket] {msg:?} before authentication! Connection terminated!");
+                        close(&mut socket, &state, owner, CloseCode::Unauthorized, "Not authenticated").await;
+                        return;
+                    },
+                    (Some(user), C2SMessage::Ping(id, sync, data)) => {
                         debug!("[WebSocket{}] C2S : Ping", owner.name());
-                        let data = into_s2c_ping(msg_vec, owner.0.clone().unwrap().uuid);
-                        match bctx.clone().unwrap().send(data) {
-                            Ok(_) => (),
-                            Err(_) => warn!("[WebSocket{}] Failed to send Ping! Maybe there's no one to send", owner.name()),
-                        };
+                        if ping_rate > 0.0 && ping_bucket.take(ping_rate, ping_rate).is_err() {
+                            debug!("[WebSocket{}] Ping rate exceeded, dropping", owner.name());
+                            continue;
+                        }
+                        if ping_size > 0.0 && ping_size_bucket.take_n(data.len() as f64, ping_size, ping_size).is_err() {
+                            debug!("[WebSocket{}] Ping size exceeded ({} bytes), dropping", owner.name(), data.len());
+                            continue;
+                        }
+                        // Encoded once, every subscriber gets the same buffer
+                        let data = S2CMessage::Ping(user.uuid, id, sync, data).to_bytes();
+                        if state.broadcasts.send(user.storage_uuid, data) == 0 {
+                            debug!("[WebSocket{}] Nobody is subscribed to the Ping", owner.name());
+                        }
                         continue;
                     },
-                    C2SMessage::Sub(uuid) => { // FIXME: Исключить возможность использования SUB без авторизации
+                    (Some(user), C2SMessage::Sub(uuid)) => {
                         debug!("[WebSocket{}] C2S : Sub", owner.name());
                         // Отбрасываю Sub на самого себя
-                        if uuid == owner.0.clone().unwrap().uuid {
+                        if uuid == user.uuid {
                             continue;
                         };
         
-                        let broadcast = state.broadcasts.lock().await;
-                        let rx =  match broadcast.get(&uuid) {
-                            Some(rx) => rx.to_owned().subscribe(),
-                            None => {
-                                warn!("[WebSocket{}] Attention! The required UUID for subscription was not found!", owner.name());
-                                let (tx, rx) = broadcast::channel(64);
-                                broadcast.insert(uuid, tx);
-                                rx
-                            },
-                        };
-                        // .to_owned().subscribe();
-                        let shutdown = Arc::new(Notify::new());
-                        tokio::spawn(subscribe(mtx.clone(), rx, shutdown.clone()));
-                        cutoff.insert(uuid, shutdown);
+                        let key = storage_uuid(&state, uuid, Some(&user.auth_system));
+                        // If the user isn't connected yet, we'll get their pings once they are
+                        state.broadcasts.subscribe(key, &outbox);
+                        subscriptions.keys.insert(uuid, key);
                         continue;
                     },
-                    C2SMessage::Unsub(uuid) => {
+                    (Some(user), C2SMessage::Unsub(uuid)) => {
                         debug!("[WebSocket{}] C2S : Unsub", owner.name());
                         // Отбрасываю Unsub на самого себя
-                        if uuid == owner.0.clone().unwrap().uuid {
+                        if uuid == user.uuid {
                             continue;
                         };
-                        let shutdown = cutoff.remove(&uuid).unwrap().1;
-                        shutdown.notify_one();
+                        if let Some(key) = subscriptions.keys.remove(&uuid) {
+                            state.broadcasts.unsubscribe(key, &outbox);
+                        }
                         continue;
                     },
                 }
         
                 // Sending message
                 debug!("[WebSocket{}] Answering: {msg:?}", owner.name());
-                if socket.send(msg).await.is_err() {
+                if send(&mut socket, &kill, msg).await.is_err() {
                     warn!("[WebSocket{}] Send error! Connection terminated!", owner.name());
                     if let Some(u) = owner.0 {
-                        remove_broadcast(state.broadcasts.clone(), u.uuid).await;
+                        remove_user(&state, &u).await;
                     }
                     return;
                 }
             }
-            msg = mrx.recv() => {
-                match socket.send(Message::Binary(msg.clone().unwrap())).await {
+            Some(msg) = mrx.recv() => {
+                let msg = match msg {
+                    SessionMessage::Binary(msg) => msg,
+                    SessionMessage::Close(code, reason) => {
+                        info!("[WebSocket{}] Closing connection with {code:?}: {reason}", owner.name());
+                        close(&mut socket, &state, owner, code, &reason).await;
+                        return;
+                    }
+                };
+                match send(&mut socket, &kill, Message::Binary(msg.clone())).await {
                     Ok(_) => {
-                        debug!("[WebSocketSubscribe{}] Answering: {}", owner.name(), hex::encode(msg.unwrap()));
+                        debug!("[WebSocketSubscribe{}] Answering: {}", owner.name(), hex::encode(msg));
                     }
                     Err(_) => {
                         warn!("[WebSocketSubscriber{}] Send error! Connection terminated!", owner.name());
                         if let Some(u) = owner.0 {
-                            remove_broadcast(state.broadcasts.clone(), u.uuid).await;
+                            remove_user(&state, &u).await;
                         }
                         return;
                     }
                 }
             }
+            msg = outbox.recv() => {
+                let (lags, skipped) = outbox.take_lags();
+                if lags != 0 {
+                    warn!("[WebSocketSubscriber{}] Too slow, skipped pings ({skipped} in total)", owner.name());
+                    if max_lag != 0.0 && (0..lags).any(|_| lag_bucket.take(max_lag / 60.0, max_lag).is_err()) {
+                        warn!("[WebSocketSubscriber{}] Lags constantly! Connection terminated!", owner.name());
+                        close(&mut socket, &state, owner, CloseCode::TryAgainLater, "Connection is too slow").await;
+                        return;
+                    }
+                }
+                if send(&mut socket, &kill, Message::Binary(msg)).await.is_err() {
+                    warn!("[WebSocketSubscriber{}] Send error! Connection terminated!", owner.name());
+                    if let Some(u) = owner.0 {
+                        remove_user(&state, &u).await;
+                    }
+                    return;
+                }
+            }
+            _ = kill.notified() => {
+                warn!("[WebSocket{}] Client doesn't read messages! Connection dropped!", owner.name());
+                if let Some(u) = owner.0 {
+                    remove_user(&state, &u).await;
+                }
+                return;
+            }
+            _ = &mut auth_timeout, if owner.0.is_none() => {
+                warn!("[WebSocket] Not authenticated in time! Connection terminated!");
+                close(&mut socket, &state, owner, CloseCode::Unauthorized, "Authentication timed out").await;
+                return;
+            }
+            Ok(()) = shutdown.changed() => {
+                info!("[WebSocket{}] Server is shutting down, closing connection", owner.name());
+                close(&mut socket, &state, owner, CloseCode::ServiceRestart, "Server is restarting").await;
+                return;
+            }
         }
     }
 }
 
-async fn subscribe(socket: mpsc::Sender<Vec<u8>>, mut rx: Receiver<Vec<u8>>, shutdown: Arc<Notify>) {
-    loop {
-        tokio::select! {
-            _ = shutdown.notified() => {
-                debug!("Shutdown SUB!");
-                return;
-            }
-            msg = rx.recv() => {
-                if socket.send(msg.unwrap()).await.is_err() {
-                    error!("Forced shutdown SUB due error!");
-                    return;
-                };
-            }
+/// Subscriptions of a connection, they are dropped together with it
+struct Subscriptions {
+    state: AppState,
+    outbox: Arc<Outbox>,
+    keys: HashMap<Uuid, Uuid>, // <requested UUID, key in broadcasts>
+}
+
+impl Drop for Subscriptions {
+    fn drop(&mut self) {
+        for key in self.keys.values() {
+            self.state.broadcasts.unsubscribe(*key, &self.outbox);
+        }
+    }
+}
+
+async fn remove_user(state: &AppState, user: &WSUser) {
+    state.broadcasts.unpublish(user.storage_uuid);
+    let connections = state.user_connections.lock().await;
+    if let Some(mut conns) = connections.get_mut(&user.storage_uuid) {
+        conns.retain(|conn| !conn.tx.same_channel(&user.tx));
+    }
+    connections.remove_if(&user.storage_uuid, |_, conns| conns.is_empty());
+}
+
+/// Registers the connection, keeping the account within maxConnections.
+/// Accounts are told apart by storage UUID, so with "namespace" players of different providers don't share the limit
+async fn add_connection(state: &AppState, storage_uuid: Uuid, conn: Connection) -> Result<(), CloseCode> {
+    let limits = &state.config.limits;
+    let connections = state.user_connections.lock().await;
+    let mut conns = connections.entry(storage_uuid).or_default();
+    let excess = match limits.max_connections {
+        0 => 0,
+        max => (conns.len() + 1).saturating_sub(max),
+    };
+    let closed: Vec<Connection> = match limits.connection_overflow {
+        _ if excess == 0 => Vec::new(),
+        ConnectionOverflow::Refuse => return Err(CloseCode::TooManyConnections),
+        ConnectionOverflow::CloseOldest => conns.drain(..excess).collect(),
+    };
+    conns.push(conn);
+    drop(conns);
+    drop(connections);
+    for conn in closed {
+        conn.send(SessionMessage::Close(CloseCode::ReAuth, String::from("Logged in from another place")));
+    }
+    Ok(())
+}
+
+/// Sends the frame unless the connection is dropped meanwhile
+async fn send(socket: &mut WebSocket, kill: &Notify, msg: Message) -> Result<(), axum::Error> {
+    tokio::select! {
+        res = socket.send(msg) => res,
+        _ = kill.notified() => Err(axum::Error::new("connection dropped")),
+    }
+}
+
+/// Sends the close frame and forgets the user. A client which doesn't read gets a second to take the frame
+async fn close(socket: &mut WebSocket, state: &AppState, owner: WSOwner, code: CloseCode, reason: &str) {
+    let frame = CloseFrame { code: code as u16, reason: reason.to_string().into() };
+    let _ = tokio::time::timeout(Duration::from_secs(1), socket.send(Message::Close(Some(frame)))).await;
+    if let Some(u) = owner.0 {
+        remove_user(state, &u).await;
+    }
+}
+
+/// Connections of the users with any provider, or of everyone if `uuids` is None
+pub async fn connections(state: &AppState, uuids: Option<&[Uuid]>) -> Vec<Connection> {
+    state.user_connections.lock().await.iter()
+        .flat_map(|entry| entry.value().clone())
+        .filter(|conn| uuids.is_none_or(|uuids| uuids.contains(&conn.uuid)))
+        .collect()
+}
+
+/// UUIDs of online users
+pub async fn online(state: &AppState) -> Vec<Uuid> {
+    let mut online: Vec<Uuid> = connections(state, None).await.iter().map(|conn| conn.uuid).collect();
+    online.sort_unstable();
+    online.dedup();
+    online
+}
+
+/// Sends the message to the user's connections, if `token` is set only to the ones which use it.
+/// Returns false if there is no such connection.
+pub async fn close_connection(state: &AppState, uuid: Uuid, token: Option<&str>, msg: SessionMessage) -> bool {
+    let mut sent = false;
+    for conn in connections(state, Some(&[uuid])).await {
+        if token.is_none_or(|token| token == conn.token) {
+            sent |= conn.send(msg.clone());
         }
     }
+    sent
 }
 
-fn into_s2c_ping(buf: Vec<u8>, uuid: Uuid) -> Vec<u8> {
-    use std::iter::once;
-    once(1).chain(uuid.into_bytes().iter().copied()).chain(buf.as_slice()[1..].iter().copied()).collect()
+/// Sends the message to every connection of the users, or of everyone if `uuids` is None.
+/// Returns the number of connections which got it.
+pub async fn send_to(state: &AppState, uuids: Option<&[Uuid]>, msg: SessionMessage) -> usize {
+    // Clients which don't keep up miss the message instead of holding everyone up
+    connections(state, uuids).await.iter().filter(|conn| conn.send(msg.clone())).count()
 }
 
-async fn remove_broadcast(broadcasts: Arc<tokio::sync::Mutex<DashMap<Uuid, broadcast::Sender<Vec<u8>>>>>, uuid: Uuid) {
-    let map = broadcasts.lock().await;
-    map.remove(&uuid);
-}
\ No newline at end of file
+/// Disconnects online users who are banned now. Bans are keyed by storage UUID, like the connections
+pub async fn kick_banned(state: &AppState) {
+    let accounts: Vec<(Uuid, Vec<Connection>)> = state.user_connections.lock().await.iter()
+        .map(|entry| (*entry.key(), entry.value().clone()))
+        .collect();
+    for (storage_uuid, conns) in accounts {
+        if let Some(ban) = banned(state, storage_uuid).await {
+            for conn in conns {
+                conn.send(SessionMessage::Close(CloseCode::Banned, ban.reason.clone()));
+            }
+        }
+    }
+}
+/// Closes every connection with 1012 and waits a bit for them to send the close frame
+pub async fn shutdown(state: &AppState) {
+    state.shutdown.send_replace(true);
+    // Every connection holds a receiver until it's closed
+    let deadline = tokio::time::Instant::now() + std::time::Duration::from_secs(1);
+    while state.shutdown.receiver_count() != 0 && tokio::time::Instant::now() < deadline {
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+    }
+}
+
+#[cfg(test)]
+#[tokio::test]
+async fn stalled_connection_is_dropped() {
+    let (tx, _rx) = mpsc::channel(1);
+    let conn = Connection { uuid: Uuid::nil(), token: String::new(), tx, kill: Arc::new(Notify::new()) };
+    assert!(conn.send(SessionMessage::Binary(Bytes::from_static(&[4]))));
+    // The client doesn't read, so the queue is full
+    assert!(!conn.send(SessionMessage::Binary(Bytes::from_static(&[4]))));
+    assert!(conn.send(SessionMessage::Close(CloseCode::Banned, String::new())));
+    tokio::time::timeout(Duration::from_secs(1), conn.kill.notified()).await.unwrap();
+}
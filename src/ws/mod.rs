@@ -2,8 +2,10 @@ mod c2s;
 mod s2c;
 mod handler;
 mod errors;
+mod broadcasts;
 
 pub use c2s::C2SMessage;
 pub use s2c::S2CMessage;
-pub use handler::handler;
-pub use errors::MessageLoadError;
\ No newline at end of file
+pub use handler::{close_connection, connections, handler, kick_banned, online, send_to, shutdown, CloseCode, Connection, SessionMessage};
+pub use errors::MessageLoadError;
+pub use broadcasts::{Broadcasts, Outbox};
\ No newline at end of file
@@ -1,22 +1,23 @@
 use super::MessageLoadError;
+use bytes::{BufMut, Bytes, BytesMut};
 use std::convert::{TryFrom, TryInto};
 
 use uuid::Uuid;
 
 #[repr(u8)]
-#[derive(Debug, Clone, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum S2CMessage<'a> {
     Auth = 0,
     Ping(Uuid, u32, bool, &'a [u8]) = 1,
     Event(Uuid) = 2, // Updates avatar for other players
-    Toast(u8, &'a str, Option<&'a str>) = 3,
+    Toast(u8, &'a str, Option<&'a str>) = 3, // (type, header, description). Header can't contain NUL
     Chat(&'a str) = 4,
     Notice(u8) = 5,
 }
 impl<'a> TryFrom<&'a [u8]> for S2CMessage<'a> {
     type Error = MessageLoadError;
     fn try_from(buf: &'a [u8]) -> Result<Self, <Self as TryFrom<&'a [u8]>>::Error> {
-        if buf.len() == 0 {
+        if buf.is_empty() {
             Err(MessageLoadError::BadLength("S2CMessage", 1, false, 0))
         } else {
             use MessageLoadError::*;
@@ -50,47 +51,124 @@ impl<'a> TryFrom<&'a [u8]> for S2CMessage<'a> {
                         Err(BadLength("S2CMessage::Event", 17, true, buf.len()))
                     }
                 }
-                3 => todo!(),
-                4 => todo!(),
-                5 => todo!(),
+                3 => {
+                    if buf.len() >= 2 {
+                        let text = std::str::from_utf8(&buf[2..]).map_err(|e| BadUtf8("S2CMessage::Toast", e))?;
+                        let (header, description) = match text.split_once('\0') {
+                            Some((header, description)) => (header, Some(description)),
+                            None => (text, None),
+                        };
+                        Ok(Toast(buf[1], header, description))
+                    } else {
+                        Err(BadLength("S2CMessage::Toast", 2, false, buf.len()))
+                    }
+                }
+                4 => std::str::from_utf8(&buf[1..])
+                    .map(Chat)
+                    .map_err(|e| BadUtf8("S2CMessage::Chat", e)),
+                5 => {
+                    if buf.len() == 2 {
+                        Ok(Notice(buf[1]))
+                    } else {
+                        Err(BadLength("S2CMessage::Notice", 2, true, buf.len()))
+                    }
+                }
                 a => Err(BadEnum("S2CMessage.type", 0..=5, a.into())),
             }
         }
     }
 }
-impl<'a> Into<Box<[u8]>> for S2CMessage<'a> {
-    fn into(self) -> Box<[u8]> {
-        use std::iter::once;
+impl<'a> From<S2CMessage<'a>> for Bytes {
+    fn from(val: S2CMessage<'a>) -> Self {
         use S2CMessage::*;
-        match self {
-            Auth => Box::new([0]),
-            Ping(u, i, s, d) => once(1)
-                .chain(u.into_bytes().iter().copied())
-                .chain(i.to_be_bytes().iter().copied())
-                .chain(once(if s { 1 } else { 0 }))
-                .chain(d.into_iter().copied())
-                .collect(),
-            Event(u) => once(2).chain(u.into_bytes().iter().copied()).collect(),
-            Toast(t, h, d) => once(3)
-                .chain(once(t))
-                .chain(h.as_bytes().into_iter().copied())
-                .chain(
-                    d.into_iter()
-                        .map(|s| once(0).chain(s.as_bytes().into_iter().copied()))
-                        .flatten(),
-                )
-                .collect(),
-            Chat(c) => once(4).chain(c.as_bytes().iter().copied()).collect(),
-            Notice(t) => Box::new([5, t]),
+        let mut buf = BytesMut::new();
+        match val {
+            Auth => buf.put_u8(0),
+            Ping(u, i, s, d) => {
+                buf.reserve(22 + d.len());
+                buf.put_u8(1);
+                buf.put_slice(u.as_bytes());
+                buf.put_u32(i);
+                buf.put_u8(s.into());
+                buf.put_slice(d);
+            }
+            Event(u) => {
+                buf.put_u8(2);
+                buf.put_slice(u.as_bytes());
+            }
+            Toast(t, h, d) => {
+                buf.put_u8(3);
+                buf.put_u8(t);
+                buf.put_slice(h.as_bytes());
+                if let Some(d) = d {
+                    buf.put_u8(0);
+                    buf.put_slice(d.as_bytes());
+                }
+            }
+            Chat(c) => {
+                buf.put_u8(4);
+                buf.put_slice(c.as_bytes());
+            }
+            Notice(t) => buf.put_slice(&[5, t]),
         }
+        buf.freeze()
     }
 }
 
 impl<'a> S2CMessage<'a> {
-    pub fn to_array(self) -> Box<[u8]> {
-        <S2CMessage as Into<Box<[u8]>>>::into(self)
+    /// Encodes the message once, the result can be shared by any number of connections
+    pub fn to_bytes(self) -> Bytes {
+        self.into()
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    proptest! {
+        #[test]
+        fn round_trip(
+            kind in 0..6u8,
+            uuid in any::<u128>().prop_map(Uuid::from_u128),
+            id in any::<u32>(),
+            sync in any::<bool>(),
+            data in any::<Vec<u8>>(),
+            byte in any::<u8>(),
+            header in "[^\0]*",
+            description in proptest::option::of(any::<String>()),
+        ) {
+            let msg = match kind {
+                0 => S2CMessage::Auth,
+                1 => S2CMessage::Ping(uuid, id, sync, &data),
+                2 => S2CMessage::Event(uuid),
+                3 => S2CMessage::Toast(byte, &header, description.as_deref()),
+                4 => S2CMessage::Chat(&header),
+                _ => S2CMessage::Notice(byte),
+            };
+            let buf = msg.to_bytes();
+            prop_assert_eq!(S2CMessage::try_from(buf.as_ref()).unwrap(), msg);
+        }
+
+        #[test]
+        fn decoding_never_panics(buf in any::<Vec<u8>>()) {
+            let _ = S2CMessage::try_from(buf.as_slice());
+        }
     }
-    pub fn to_vec(self) -> Vec<u8> {
-        self.to_array().to_vec()
+
+    #[test]
+    fn ping_is_encoded_once() {
+        use crate::ws::C2SMessage;
+
+        let uuid = Uuid::from_u128(1);
+        let received: Bytes = C2SMessage::Ping(7, true, b"data").into();
+        // As the handler relays it
+        let Ok(C2SMessage::Ping(id, sync, data)) = C2SMessage::try_from(received.as_ref()) else {
+            panic!("not a ping");
+        };
+        let frame = S2CMessage::Ping(uuid, id, sync, data).to_bytes();
+        assert_eq!(frame, [&[1][..], uuid.as_bytes(), &[0, 0, 0, 7, 1], b"data"].concat());
+        // Every subscriber gets the same buffer
+        assert_eq!(frame.clone().as_ptr(), frame.as_ptr());
     }
-}
\ No newline at end of file
+}